@@ -1,4 +1,3 @@
-
 use anyhow::{anyhow, bail};
 use anyhow::{Result};
 use clap::{Parser};
@@ -6,306 +5,4509 @@ use dialoguer::Confirm;
 use env_logger::{Env, Target};
 use globwalk::{FileType as GlobFileType, glob_builder};
 use lazy_static::lazy_static;
-use log::{info, debug, warn, error};
+use log::{info, debug, warn, error, LevelFilter, Log, Metadata, Record};
 use path_absolutize::*;
 use regex::{Regex, escape};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsStr;
 use std::fs::{create_dir_all};
-use std::path::{Path};
-use std::process::Command;
+use std::path::{Component, Path, PathBuf};
+use std::io::{IsTerminal, Read, Write};
+use std::process::{Command, Output, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+const DEFAULT_GEOMETRY: &str = "1000x1400^";
+/// The gm geometry flags, replaced by --fit.
+const GEOMETRY_MODIFIERS: &[char] = &['^', '!', '>', '<'];
+const DEFAULT_QUALITY: &str = "80";
+/// Default quality per output format, each on its encoder's own scale: WebP reaches JPEG's
+/// look at a lower number, AVIF still lower. Formats not listed use DEFAULT_QUALITY.
+const FORMAT_QUALITIES: &[(&str, &str)] = &[("jpg", "82"), ("jpeg", "82"), ("webp", "78"), ("avif", "50")];
+const DEFAULT_EXTENSION: &str = "jpg";
+/// Files and directories left behind by file managers, dropped unless --no-default-excludes.
+const DEFAULT_EXCLUDES: [&str; 4] = ["__MACOSX", ".DS_Store", "Thumbs.db", "desktop.ini"];
+/// Entry written at the root of outputs with --marker, recognized on sources as already processed.
+const MARKER_NAME: &str = ".img-optim";
+/// Per-page settings, read from this entry at the root of a source or from `<source>.img-optim-pages.json`.
+const PAGES_NAME: &str = ".img-optim-pages.json";
 
-#[derive(Parser, Clone)]
+#[derive(Parser, Clone, Serialize)]
 #[command(version = "1.0", author = "Mickaël Leduque <mleduque@gmail.com>")]
 struct Opts {
+    #[clap(required_unless_present_any = ["list_formats", "glob", "watch"], default_value = "")]
     source: String,
+    #[clap(required_unless_present_any = ["list_formats", "glob", "merge", "watch"], default_value = "")]
     target: String,
-    #[clap(long, short)]
+    /// output quality; `0` or `copy` keeps images untouched [env: IMG_OPTIM_QUALITY]
+    #[clap(long, short, conflicts_with = "target_ssim")]
     quality: Option<String>,
-    #[clap(long, short)]
+    /// [env: IMG_OPTIM_GEOMETRY]
+    #[clap(long, short, conflicts_with_all = ["max_width", "max_height"])]
     geometry: Option<String>,
+    /// geometry of the cover, the first image of each archive, instead of --geometry/--max-width/--max-height
+    #[clap(long)]
+    cover_geometry: Option<String>,
+    /// quality of the cover, the first image of each archive, instead of --quality/--quality-for/--target-ssim
+    #[clap(long)]
+    cover_quality: Option<String>,
+    /// convert the images bigger (in bytes) than this percentile of the archive's images with --aggressive-quality
+    #[clap(long, requires = "aggressive_quality", value_parser = clap::value_parser!(u8).range(1..100))]
+    aggressive_above_percentile: Option<u8>,
+    /// quality of the images above --aggressive-above-percentile, replacing --quality/--quality-for/--target-ssim
+    #[clap(long, requires = "aggressive_above_percentile")]
+    aggressive_quality: Option<String>,
     #[clap(long, short)]
     define: Option<String>,
+    /// [env: IMG_OPTIM_EXTENSION]
     #[clap(long, short)]
     extension: Option<String>,
+    /// output format as a MIME type (`image/webp`...), an alternative to --extension
+    #[clap(long, conflicts_with = "extension")]
+    output_mime: Option<String>,
     #[clap(long, short)]
     many: Option<String>,
+    /// combine the --source / --from-list archives, in that order, into a single target,
+    /// each one unpacked below a `NN-<stem>/` directory; the only positional argument is then the target
+    #[clap(long, conflicts_with = "many")]
+    merge: bool,
+    /// an archive to merge, can be repeated
+    #[clap(long = "source", requires = "merge")]
+    merge_sources: Vec<String>,
+    /// a file listing archives to merge, one per line, after the --source ones
+    #[clap(long, requires = "merge")]
+    from_list: Option<String>,
+    /// with --many, don't list the planned source => target pairs before confirming
+    #[clap(long, requires = "many", conflicts_with = "plan_limit")]
+    no_plan: bool,
+    /// with --many, list only the first N planned pairs
+    #[clap(long, requires = "many")]
+    plan_limit: Option<usize>,
+    /// with --many, process only the first N archives (in name order), to try settings on a few
+    #[clap(long, requires = "many")]
+    limit_n: Option<usize>,
+    /// with --many, write the planned archives and their effective options as JSON to this file (`-` for stdout), then exit
+    #[clap(long, requires = "many")]
+    plan_json: Option<String>,
+    /// with --many, start without asking for confirmation
+    #[clap(long, requires = "many")]
+    yes: bool,
+    /// with --many, keep this file updated with the batch progress and ETA, as JSON
+    #[clap(long, requires = "many")]
+    progress_file: Option<String>,
+    /// print the glob and regex made from the --many pattern, the files matched and the tokens captured, then exit
+    #[clap(long, requires = "many")]
+    explain_glob: bool,
+    /// write a timeline of the run (unpack, process, repack, every gm call) to this file, in Chrome tracing JSON
+    #[clap(long)]
+    trace: Option<String>,
+    /// with --many, stop at the first archive that fails instead of going on with the others
+    #[clap(long, requires = "many")]
+    fail_fast: bool,
+    /// before unpacking, wait until the temp volume has room for the archive plus this many bytes
+    #[clap(long)]
+    min_free_space: Option<u64>,
+    /// give the output (every file of it with --no-repack) the modification time of the source
+    #[clap(long)]
+    preserve_mtime: bool,
     #[clap(long, short)]
     no_repack: Option<bool>,
+    /// only process sources modified within this duration (e.g. `7d`, `12h`), with --many
+    #[clap(long, conflicts_with = "since_date")]
+    since: Option<String>,
+    /// only process sources modified after this date (`YYYY-MM-DD` or `YYYY-MM-DD HH:MM:SS`, UTC), with --many
+    #[clap(long)]
+    since_date: Option<String>,
+    /// chroma subsampling (JPEG via `-sampling-factor`; lossy WebP is always 4:2:0, `444` enables sharp YUV conversion)
+    #[clap(long, value_parser = ["444", "422", "420"])]
+    subsampling: Option<String>,
+    /// hard cap on the number of concurrent `gm` processes
+    #[clap(long)]
+    max_gm_processes: Option<usize>,
+    /// number of files processed in parallel, or `auto` to size it from CPUs and available memory
+    #[clap(long, default_value = "1")]
+    jobs: String,
+    /// encoder effort from 0 (fastest) to 10 (smallest), mapped to WebP `method` and AVIF `speed`
+    #[clap(long, default_value_t = 5, value_parser = clap::value_parser!(u8).range(0..=10))]
+    effort: u8,
+    /// AV1 encoder libavif uses for AVIF output, checked against the encoders of the installed libavif
+    #[clap(long, value_parser = ["aom", "rav1e", "svt"])]
+    avif_encoder: Option<String>,
+    /// encoder threads per AVIF image
+    #[clap(long)]
+    avif_threads: Option<u32>,
+    /// resolution written in the output pages' metadata, in pixels per inch (pixel size is unchanged)
+    #[clap(long, conflicts_with = "strip_dpi")]
+    set_dpi: Option<u32>,
+    /// write no resolution unit in the output pages, so readers don't scale them by DPI
+    #[clap(long)]
+    strip_dpi: bool,
+    /// bits per channel of the output pages, e.g. 8 to keep a 16-bit PNG source from making a 16-bit output
+    #[clap(long = "depth", value_parser = clap::value_parser!(u8).range(8..=16))]
+    bit_depth: Option<u8>,
+    /// keep PNG sources as PNG, quantized to a palette by `pngquant` after gm's resize
+    #[clap(long)]
+    png_lossy: bool,
+    /// pngquant quality range with --png-lossy, as `MIN-MAX` (0-100); an image not reaching MIN keeps gm's lossless PNG
+    #[clap(long, default_value = "65-85", requires = "png_lossy", value_parser = parse_png_quality)]
+    png_quality: String,
+    /// convert CMYK source images to RGB, which many readers otherwise show with inverted colors
+    #[clap(long)]
+    force_rgb: bool,
+    /// stretch the colors of each page to the full range (gm `-normalize`), before resizing, for faded scans
+    #[clap(long = "normalize")]
+    normalize_contrast: bool,
+    /// clip the darkest BLACK% and brightest WHITE% of the intensity range and stretch the rest, as `BLACKxWHITE`
+    #[clap(long, value_parser = parse_contrast_stretch)]
+    contrast_stretch: Option<String>,
+    /// only bake the EXIF orientation of JPEG sources, losslessly with `jpegtran`, instead of resizing and re-encoding them
+    #[clap(long)]
+    rotate_exif_only: bool,
+    /// raw argument appended to `gm convert` before the output path (repeatable, not validated: misuse can break output)
+    #[clap(long, allow_hyphen_values = true)]
+    gm_arg: Vec<String>,
+    /// environment variable for the gm conversions, as `KEY=VALUE` (repeatable, e.g. `OMP_NUM_THREADS=1`, `MAGICK_TMPDIR=/scratch`)
+    #[clap(long, value_parser = parse_gm_env)]
+    gm_env: Vec<String>,
+    /// compression for zip output (a `.tar.zst` target is always zstd)
+    #[clap(long, value_parser = ["deflate", "zstd"])]
+    compression: Option<String>,
+    /// write zip entries with data descriptors, readable front to back without the central directory (deflate only)
+    #[clap(long)]
+    streamable: bool,
+    /// store already-compressed images as is in zip output, and compress only the other entries
+    #[clap(long)]
+    smart_compression: bool,
+    /// kill a `gm` invocation running longer than this many seconds (the file is then reported as failed)
+    #[clap(long)]
+    image_timeout: Option<u64>,
+    /// copy every file unchanged, only converting the container format
+    #[clap(long)]
+    copy_only: bool,
+    /// keep entries whose CRC doesn't match the archive's, with a warning, instead of failing on them
+    #[clap(long)]
+    no_verify_crc: bool,
+    /// recognize images by their content rather than their extension, renaming a misnamed one after its real format
+    #[clap(long)]
+    sniff: bool,
+    /// keep only the images, dropping every other file (`ComicInfo.xml`, thumbnails...)
+    #[clap(long, conflicts_with = "copy_only")]
+    images_only: bool,
+    /// only convert the images from START to END, 1-based and inclusive over the sorted image list (`10:20`, `10:`, `:20`)
+    #[clap(long, value_parser = parse_page_range)]
+    page_range: Option<String>,
+    /// what becomes of the images outside --page-range: copied unchanged or left out of the output
+    #[clap(long, default_value = "copy", value_parser = ["copy", "drop"], requires = "page_range")]
+    page_range_others: String,
+    /// keep the file manager junk (`__MACOSX/`, `.DS_Store`, `Thumbs.db`, `desktop.ini`) instead of dropping it
+    #[clap(long)]
+    no_default_excludes: bool,
+    /// add a `.img-optim` entry to the output recording the version and settings, so that a later run skips it
+    #[clap(long)]
+    marker: bool,
+    /// add the --report records of the archive to the output, as a JSON entry, so that their provenance travels with it
+    #[clap(long)]
+    embed_report: bool,
+    /// name of the --embed-report entry, at the root of the output
+    #[clap(long, default_value = ".img-optim-report.json", requires = "embed_report")]
+    embed_report_name: String,
+    /// also embed the report with `--no-repack true`, where it is skipped by default
+    #[clap(long, requires = "embed_report")]
+    embed_report_in_dir: bool,
+    /// process sources holding a `.img-optim` marker anyway, instead of skipping them as already optimized
+    #[clap(long)]
+    reprocess: bool,
+    /// warn when the pages of an archive come in more than one image format, with the count of each
+    #[clap(long)]
+    warn_mixed_formats: bool,
+    /// fail when an archive contains no image at all, instead of only warning
+    #[clap(long)]
+    fail_on_empty: bool,
+    /// keep the unpack and processed temp dirs after the run, to inspect intermediate files
+    #[clap(long)]
+    keep_temp: bool,
+    /// keep a copy, the gm command, its stderr and its output of the images matching this glob (relative to the archive root), and print their gm command
+    #[clap(long, value_parser = parse_debug_image)]
+    debug_image: Option<String>,
+    /// target name template for --many, with `{stem}`, `{parent}`, `{date}` and `{token}` placeholders
+    #[clap(long)]
+    target_template: Option<String>,
+    /// also write a thumbnail grid of the pages of each archive to this path, with the target's `{stem}`, `{parent}` and `{date}`
+    #[clap(long)]
+    contact_sheet: Option<String>,
+    /// pages per row of the --contact-sheet
+    #[clap(long, default_value_t = 6, requires = "contact_sheet")]
+    contact_sheet_columns: u32,
+    /// largest side of a --contact-sheet thumbnail, in pixels
+    #[clap(long, default_value_t = 200, requires = "contact_sheet")]
+    contact_sheet_size: u32,
+    /// remove only the EXIF GPS data from produced JPEG/WebP/PNG files, keeping other metadata
+    #[clap(long)]
+    strip_gps: bool,
+    /// refuse archives declaring more than this many entries
+    #[clap(long)]
+    max_entries: Option<usize>,
+    /// abort extraction once the uncompressed total exceeds this many bytes
+    #[clap(long)]
+    max_uncompressed: Option<u64>,
+    /// normalize `\` separators in entry names on unpack, renaming entries that would collide
+    #[clap(long)]
+    normalize_names: bool,
+    /// also lowercase entry names (with --normalize-names)
+    #[clap(long, requires = "normalize_names")]
+    lowercase_names: bool,
+    /// rename produced entries so their names only use --safe-chars, runs of other characters becoming `_`
+    #[clap(long, conflicts_with_all = ["update", "content_cache"])]
+    sanitize_names: bool,
+    /// characters kept by --sanitize-names, as a regex character class body
+    #[clap(long, default_value = "A-Za-z0-9._-", requires = "sanitize_names")]
+    safe_chars: String,
+    /// reduce output to at most this many colors (gm `-colors`)
+    #[clap(long)]
+    colors: Option<u32>,
+    /// dither when reducing colors (only for --colors or GIF output; independent of --quality)
+    #[clap(long, conflicts_with = "no_dither")]
+    dither: bool,
+    /// never dither when reducing colors
+    #[clap(long)]
+    no_dither: bool,
+    /// record the original image sizes of each source in this JSON file on its first run, and report how they drifted on later runs
+    #[clap(long)]
+    baseline: Option<String>,
+    /// write the SHA-256 of each produced archive to this file (`sha256sum` format)
+    #[clap(long)]
+    manifest: Option<String>,
+    /// also list the SHA-256 of every entry in the manifest, indented under its archive
+    #[clap(long, requires = "manifest")]
+    manifest_entries: bool,
+    /// append one JSON line per processed image (outputs, duration_ms, backend) to this file
+    #[clap(long)]
+    report: Option<String>,
+    /// what to do when the target already exists (default `prompt` when interactive, `skip` otherwise)
+    #[clap(long, value_parser = ["skip", "overwrite", "prompt"])]
+    overwrite: Option<String>,
+    /// rebuild an existing target zip, only converting images newer than their entries in it
+    /// (a --deterministic target has no usable timestamps and is converted again in full)
+    #[clap(long, conflicts_with_all = ["overwrite", "no_repack"])]
+    update: bool,
+    /// like --update, but an image is reused when its content hash is unchanged, as recorded
+    /// in a `<target>.img-optim-cache.json` sidecar
+    #[clap(long, conflicts_with_all = ["overwrite", "no_repack"])]
+    content_cache: bool,
+    /// split the output into `<stem>.part1.<ext>`, `<stem>.part2.<ext>`... of at most this many bytes each
+    #[clap(long, conflicts_with_all = ["no_repack", "update", "content_cache"])]
+    split_size: Option<u64>,
+    /// write entries in sorted order with normalized timestamps, for reproducible archives
+    #[clap(long)]
+    deterministic: bool,
+    /// resampling filter used when resizing (gm `-filter`)
+    #[clap(long, ignore_case = true, value_parser = [
+        "point", "box", "triangle", "hermite", "hanning", "hamming", "blackman", "gaussian",
+        "quadratic", "cubic", "catrom", "mitchell", "lanczos", "bessel", "sinc",
+    ])]
+    resize_filter: Option<String>,
+    /// crop uniform borders before resizing (gm `-trim +repage`)
+    #[clap(long)]
+    trim: bool,
+    /// color tolerance for --trim, in percent (aggressive values can crop actual content)
+    #[clap(long, requires = "trim")]
+    trim_fuzz: Option<f32>,
+    /// file of `token=quality` lines overriding --quality per archive matched by --many
+    #[clap(long)]
+    quality_map: Option<String>,
+    /// print the effective configuration, defaults included, as JSON and exit
+    #[clap(long)]
+    #[serde(skip)]
+    print_config: bool,
+    /// quality for images with a given source extension, as `EXT=QUALITY` (repeatable, e.g. `png=copy`)
+    #[clap(long)]
+    quality_for: Vec<String>,
+    /// default quality for an output format when --quality isn't given, as `EXT=QUALITY` (repeatable,
+    /// overrides the built-in jpg=82, webp=78, avif=50)
+    #[clap(long, value_parser = parse_format_quality)]
+    format_quality: Vec<String>,
+    /// transcode every image to this format, whatever its source type
+    #[clap(long, conflicts_with = "extension")]
+    normalize_format: Option<String>,
+    /// keep animated GIFs as GIF, optimizing their frames with `gifsicle` instead of converting them with gm
+    #[clap(long)]
+    gifsicle: bool,
+    /// gifsicle optimization level with --gifsicle (`-O1` to `-O3`)
+    #[clap(long, default_value_t = 3, requires = "gifsicle", value_parser = clap::value_parser!(u8).range(1..=3))]
+    gif_optimize_level: u8,
+    /// let gifsicle alter pixels for smaller frames with --gifsicle (`--lossy`, e.g. 30 to 200, higher is smaller)
+    #[clap(long, requires = "gifsicle")]
+    gif_lossy: Option<u32>,
+    /// with --normalize-format, flatten animated GIFs to their first frame or fail on them
+    #[clap(long, value_parser = ["first-frame", "error"], requires = "normalize_format")]
+    animated_gif: Option<String>,
+    /// produce one output per width instead of using --geometry, e.g. `320,800,1600` gives `page@320.jpg`...
+    #[clap(long, value_delimiter = ',')]
+    sizes: Vec<u32>,
+    /// with --no-repack, replace identical output files by hardlinks to the first one
+    #[clap(long)]
+    hardlink_duplicates: bool,
+    /// also write log messages to this file (appended to by default)
+    #[clap(long)]
+    log_file: Option<String>,
+    /// level of the messages written to --log-file
+    #[clap(long, default_value = "info", requires = "log_file")]
+    log_file_level: LevelFilter,
+    /// move an existing --log-file to `<file>.1` instead of appending to it
+    #[clap(long, requires = "log_file")]
+    log_file_rotate: bool,
+    /// resolution used to rasterize the pages of a PDF source
+    #[clap(long, default_value_t = 150)]
+    pdf_dpi: u32,
+    /// how the geometry's `WxH` box applies: `contain` fits within it, `cover` fills it and crops the overflow, `stretch` ignores the aspect ratio
+    #[clap(long, value_parser = ["contain", "cover", "stretch"])]
+    fit: Option<String>,
+    /// crop every page to exactly `WxH` after the resize, for readers wanting uniform page dimensions
+    #[clap(long, value_parser = parse_crop_to)]
+    crop_to: Option<String>,
+    /// part of the page kept by --crop-to or `--fit cover` (default `center`)
+    #[clap(long, value_parser = ["center", "north", "south", "east", "west", "northwest", "northeast", "southwest", "southeast"])]
+    crop_gravity: Option<String>,
+    /// shrink images wider than this, keeping the aspect ratio; never enlarges (gm `-resize WxH>`)
+    #[clap(long)]
+    max_width: Option<u32>,
+    /// shrink images taller than this, keeping the aspect ratio; never enlarges (gm `-resize WxH>`)
+    #[clap(long)]
+    max_height: Option<u32>,
+    /// don't carry the source archive comment over to the output
+    #[clap(long)]
+    drop_comments: bool,
+    /// how deep archives nested inside the source are optimized too, deeper ones are copied as is
+    #[clap(long, default_value_t = 1)]
+    max_depth: usize,
+    /// nesting level of the archive being processed, 0 for the source itself
+    #[clap(skip)]
+    #[serde(skip)]
+    depth: usize,
+    /// copy images unchanged when their largest side is below this many pixels
+    #[clap(long)]
+    min_dimension: Option<u32>,
+    /// flag images whose longest side is more than this many times their shortest, e.g. 1px-tall strips
+    #[clap(long)]
+    max_aspect_ratio: Option<f64>,
+    /// only apply the geometry to images with a side over this many pixels, others are just recompressed (--sizes always resize)
+    #[clap(long)]
+    resize_threshold: Option<u32>,
+    /// what to do with images over --max-aspect-ratio: copy them unchanged, or fail them
+    #[clap(long, value_parser = ["skip", "error"], default_value = "skip", requires = "max_aspect_ratio")]
+    aspect_policy: String,
+    /// leave zero-length files (placeholders) out of the output, images included
+    #[clap(long)]
+    drop_empty: bool,
+    /// what to do with zero-length images, which gm can't convert: copy them unchanged, or fail them
+    #[clap(long, value_parser = ["skip", "error"], default_value = "error", conflicts_with = "drop_empty")]
+    empty_policy: String,
+    /// rotate images clockwise by this many degrees (only landscape ones with --auto-rotate-landscape)
+    #[clap(long, value_parser = ["90", "180", "270"])]
+    rotate: Option<String>,
+    /// rotate images wider than tall to portrait, by --rotate degrees or 90 (spreads are not split)
+    #[clap(long)]
+    auto_rotate_landscape: bool,
+    /// split pages wider than tall into two pages, suffixed `-1` and `-2`
+    #[clap(long)]
+    split_spreads: bool,
+    /// which half of a split spread comes first, overriding --reading-direction
+    #[clap(long, value_parser = ["left-first", "right-first"], requires = "split_spreads")]
+    split_order: Option<String>,
+    /// reading direction of the pages; with `rtl` the right half of a split spread becomes
+    /// the `-1` page, so it precedes the left half in the archive
+    #[clap(long, value_parser = ["ltr", "rtl"])]
+    reading_direction: Option<String>,
+    /// write side-by-side original/optimized montages of a few images to this directory
+    #[clap(long)]
+    compare: Option<String>,
+    /// how many images --compare picks, spread over each archive
+    #[clap(long, default_value_t = 5, requires = "compare")]
+    sample: usize,
+    /// measure the distortion of every optimized image against its source
+    #[clap(long, value_parser = ["ssim", "psnr"])]
+    measure: Option<String>,
+    /// warn about images whose SSIM falls below this value (implies --measure ssim)
+    #[clap(long, conflicts_with = "measure")]
+    min_ssim: Option<f64>,
+    /// pick, per image, the lowest quality whose output still reaches this SSIM
+    #[clap(long)]
+    target_ssim: Option<f64>,
+    /// maximum number of encodes tried per image by --target-ssim
+    #[clap(long, default_value_t = 6, requires = "target_ssim")]
+    target_ssim_iterations: u32,
+    /// list the output formats the installed gm can write, and exit
+    #[clap(long)]
+    #[serde(skip)]
+    list_formats: bool,
+    /// optimize the image files matching this pattern directly, without any archive;
+    /// the image options and --jobs apply as for archive entries, other files are skipped
+    #[clap(long, requires = "out_dir")]
+    glob: Option<String>,
+    /// with --glob, where optimized images are written, mirroring their path below the pattern's base directory;
+    /// with --watch, where the archives are written
+    #[clap(long)]
+    out_dir: Option<String>,
+    /// keep running, processing each archive that lands in this directory into --out-dir once it stops growing
+    #[clap(long, requires = "out_dir", conflicts_with_all = ["glob", "many", "merge"])]
+    watch: Option<String>,
+    /// seconds between two scans of the --watch directory; an archive is processed once unchanged over a whole interval
+    #[clap(long, default_value_t = 5, requires = "watch")]
+    watch_interval: u64,
+    /// with --watch, move the source archives here once processed, instead of leaving them in place
+    #[clap(long, requires = "watch")]
+    watch_done: Option<String>,
 }
 
-fn main() -> Result<()> {
-    env_logger::Builder::from_env(Env::default().default_filter_or("debug"))
-                            .target(Target::Stdout)
-                            .init();
+/// One line of `gm convert -list format`.
+#[derive(Clone, Debug)]
+struct GmFormat {
+    name: String,
+    readable: bool,
+    writable: bool,
+}
 
-    let opts: Opts = Opts::parse();
-    match &opts.many {
-        Some(pattern) => {
-            let parts = resolve_pattern(&opts, pattern)?;
-            for part in &parts {
-                println!("{} => {}", part.source, part.target);
-            }
+/// Settings of one `gm convert` run, computed per image on top of `Opts`.
+#[derive(Clone)]
+struct Conversion {
+    input: String,
+    result: PathBuf,
+    /// None keeps the native size
+    geometry: Option<String>,
+    quality: String,
+    rotate: Option<String>,
+    /// region to keep, cropped before anything else
+    crop: Option<String>,
+    /// the source is CMYK and must be converted to RGB (--force-rgb)
+    to_rgb: bool,
+    /// region kept after the resize (--fit cover)
+    fit_crop: Option<String>,
+    /// the source has an EXIF orientation, applied before anything else
+    auto_orient: bool,
+    /// --debug-image directory receiving the gm command, its stderr and its output
+    debug_dir: Option<PathBuf>,
+}
 
-            if Confirm::new().with_prompt("Do you want to continue?").interact()? {
-                println!("Processing archives...");
-                for part in parts.iter() {
-                    process_archive(&part)?;
-                }
-            }
-            Ok(())
-        },
-        None => process_archive(&opts),
-    }
+/// Archive-level data read while unpacking that must survive the repack.
+#[derive(Default)]
+struct ArchiveMetadata {
+    comment: Vec<u8>,
+    /// modification time of every unpacked file, by path below the unpack directory
+    modified: HashMap<PathBuf, zip::DateTime>,
 }
 
-fn resolve_pattern(opts:&Opts, pattern: &str) -> Result<Vec<Opts>> {
-    let pattern_len = pattern.len();
-    let glob_pattern = if !opts.source.contains(pattern) {
-        bail!("source name {} doesn't contain pattern {}",opts.source, pattern);
-    } else {
-        let escaped = escape_glob(&opts.source);
-        let glob = escaped.replace(pattern, &"?".repeat(pattern_len));
-        debug!("using glob '{}'", glob);
-        glob
-    };
-    let source_regex = if !opts.source.contains(pattern) {
-        bail!("source name {} doesn't contain pattern {}",opts.source, pattern);
-    } else {
-        let regex_pattern = escape(&opts.source).replace(pattern, &format!("(.{{{}}})", pattern_len));
-        debug!("using pattern '{}'", regex_pattern);
-        Regex::new(&regex_pattern)?
-    };
+/// The --content-cache sidecar of a target:
+/// `{"options": "<sha256 of the image options>", "entries": {"<source entry>": {"sha256": "...", "outputs": ["<target entry>", ...]}}}`.
+#[derive(Default, Debug, Serialize, Deserialize)]
+struct ContentCache {
+    options: String,
+    entries: HashMap<String, CachedImage>,
+}
 
-    if !opts.target.contains(pattern) {
-        bail!("target name {} doesn't contain pattern {}",opts.target, pattern);
+/// The --baseline file: `{"archives": {"<source>": {"recorded": "<date>", "sizes": {"<entry>": <bytes>, ...}}}}`.
+#[derive(Default, Debug, Serialize, Deserialize)]
+struct Baseline {
+    archives: BTreeMap<String, BaselineArchive>,
+}
+
+/// Source image sizes of one archive, as found on its first run.
+#[derive(Debug, Serialize, Deserialize)]
+struct BaselineArchive {
+    recorded: String,
+    sizes: BTreeMap<String, u64>,
+}
+
+impl Baseline {
+    fn load(path: &Path) -> Result<Baseline> {
+        if !path.exists() {
+            return Ok(Baseline::default());
+        }
+        serde_json::from_str(&std::fs::read_to_string(path)?)
+            .map_err(|error| anyhow!("invalid --baseline {:?}: {}", path, error))
     }
 
-    let walker = glob_builder(glob_pattern)
-        .file_type(GlobFileType::FILE )
-        .sort_by(|a, b| a.path().to_str().unwrap().cmp(b.path().to_str().unwrap()))
-        .build()?
-        .into_iter()
-        .filter_map(Result::ok);
+    fn save(&self, path: &Path) -> Result<()> {
+        let content = serde_json::to_string_pretty(self)?;
+        write_atomically(path, |partial| Ok(std::fs::write(partial, &content)?))
+    }
+}
 
-    let mut result = vec![];
-    for entry in walker {
-        let path = entry.path().as_os_str().to_str().unwrap();
-        debug!("file: {}", path);
-        match source_regex.captures(path) {
-            None => bail!("no idea what happened"),
-            Some(captures) => {
-                let capture = captures.get(1).unwrap();
-                let value = capture.as_str();
-                let target_name = opts.target.replace(pattern, value);
-                result.push(Opts {
-                    source: path.to_string(),
-                    target: target_name,
-                    many: None,
-                    ..opts.clone()
-                });
+/// Settings of one page in the pages sidecar, which take precedence over every other option for it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PageOptions {
+    quality: Option<String>,
+    extension: Option<String>,
+    /// copy the page unchanged
+    #[serde(default)]
+    skip: bool,
+}
+
+impl PageOptions {
+    /// `opts` with this page's settings on top.
+    fn apply(&self, opts: &Opts) -> Opts {
+        let mut page = opts.clone();
+        let quality = if self.skip { Some("copy".to_string()) } else { self.quality.clone() };
+        if quality.is_some() {
+            page.quality = quality;
+            page.quality_for = vec![];
+            page.target_ssim = None;
+        }
+        if let Some(extension) = &self.extension {
+            if page.normalize_format.is_some() {
+                page.normalize_format = Some(extension.clone());
+            } else {
+                page.extension = Some(extension.clone());
             }
         }
+        page
     }
-    Ok(result)
 }
 
-fn process_archive(opts: &Opts)-> Result<()> {
-    info!("creating temp dirs");
-    let unpack_dir = tempfile::Builder::new().prefix("img-optim-unpack").tempdir()?;
-    let processed_dir = tempfile::Builder::new().prefix("img-optim-processed").tempdir()?;
-    info!("temp dirs created [unpack_dir={:?} processed_dir={:?}]", unpack_dir, processed_dir);
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CachedImage {
+    sha256: String,
+    outputs: Vec<String>,
+}
 
-    let target_zip = Path::new(&opts.target).absolutize()?;
-    info!("target zip path: {:?}",target_zip);
+impl ContentCache {
+    fn path(target: &Path) -> PathBuf {
+        let mut name = target.as_os_str().to_owned();
+        name.push(".img-optim-cache.json");
+        PathBuf::from(name)
+    }
 
-    info!("start unpacking");
-    unpack_archive(&Path::new(&opts.source).absolutize()?.to_owned(), &unpack_dir)?;
-    info!("unpacking done");
+    /// Reads the sidecar of `target`, if it was written with the same image options.
+    fn load(target: &Path, opts: &Opts, page_options: &HashMap<String, PageOptions>) -> Result<Option<ContentCache>> {
+        let path = ContentCache::path(target);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let cache: ContentCache = serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+        if cache.options != image_options_hash(opts, page_options)? {
+            info!("image options changed since {:?} was written, ignoring it", path);
+            return Ok(None);
+        }
+        Ok(Some(cache))
+    }
 
-    info!("start processing files");
-    process_files(&unpack_dir.path(), &processed_dir.path(), &opts)?;
-    info!("processing done");
+    fn save(&self, target: &Path) -> Result<()> {
+        let content = serde_json::to_string_pretty(self)?;
+        write_atomically(&ContentCache::path(target), |partial| Ok(std::fs::write(partial, &content)?))
+    }
+}
 
-    let result = match opts.no_repack {
-        None | Some(false) => {
-            info!("start zipping output");
-            let result = repack_output(&processed_dir, &target_zip);
-            info!("zipping done");
-            result
+/// The options that change how an image is converted, hashed by `image_options_hash`.
+#[derive(Serialize)]
+struct ImageOptions<'a> {
+    quality: &'a Option<String>,
+    geometry: &'a Option<String>,
+    max_width: &'a Option<u32>,
+    max_height: &'a Option<u32>,
+    fit: &'a Option<String>,
+    crop_to: &'a Option<String>,
+    crop_gravity: &'a Option<String>,
+    resize_threshold: &'a Option<u32>,
+    min_dimension: &'a Option<u32>,
+    cover_geometry: &'a Option<String>,
+    cover_quality: &'a Option<String>,
+    aggressive_above_percentile: &'a Option<u8>,
+    aggressive_quality: &'a Option<String>,
+    quality_for: &'a Vec<String>,
+    format_quality: &'a Vec<String>,
+    target_ssim: &'a Option<f64>,
+    target_ssim_iterations: &'a u32,
+    extension: &'a Option<String>,
+    normalize_format: &'a Option<String>,
+    define: &'a Option<String>,
+    subsampling: &'a Option<String>,
+    effort: &'a u8,
+    avif_encoder: &'a Option<String>,
+    avif_threads: &'a Option<u32>,
+    set_dpi: &'a Option<u32>,
+    strip_dpi: &'a bool,
+    bit_depth: &'a Option<u8>,
+    png_lossy: &'a bool,
+    png_quality: &'a String,
+    force_rgb: &'a bool,
+    normalize_contrast: &'a bool,
+    contrast_stretch: &'a Option<String>,
+    rotate_exif_only: &'a bool,
+    colors: &'a Option<u32>,
+    dither: &'a bool,
+    no_dither: &'a bool,
+    resize_filter: &'a Option<String>,
+    trim: &'a bool,
+    trim_fuzz: &'a Option<f32>,
+    strip_gps: &'a bool,
+    gifsicle: &'a bool,
+    gif_optimize_level: &'a u8,
+    gif_lossy: &'a Option<u32>,
+    animated_gif: &'a Option<String>,
+    sizes: &'a Vec<u32>,
+    rotate: &'a Option<String>,
+    auto_rotate_landscape: &'a bool,
+    split_spreads: &'a bool,
+    split_order: &'a Option<String>,
+    reading_direction: &'a Option<String>,
+    max_aspect_ratio: &'a Option<f64>,
+    aspect_policy: &'a String,
+    sniff: &'a bool,
+    copy_only: &'a bool,
+    page_range: &'a Option<String>,
+    page_range_others: &'a String,
+    drop_empty: &'a bool,
+    empty_policy: &'a String,
+    pdf_dpi: &'a u32,
+    gm_arg: &'a Vec<String>,
+    gm_env: &'a Vec<String>,
+    /// the pages sidecar settings, by entry
+    pages: BTreeMap<&'a String, &'a PageOptions>,
+}
+
+impl<'a> ImageOptions<'a> {
+    fn new(opts: &'a Opts, page_options: &'a HashMap<String, PageOptions>) -> ImageOptions<'a> {
+        // every field is named, so a new option must be sorted in or out here
+        let Opts {
+            quality, geometry, max_width, max_height, fit, crop_to, crop_gravity, resize_threshold,
+            min_dimension, cover_geometry, cover_quality, aggressive_above_percentile, aggressive_quality,
+            quality_for, format_quality, target_ssim, target_ssim_iterations, extension, normalize_format,
+            define, subsampling, effort, avif_encoder, avif_threads, set_dpi, strip_dpi, bit_depth, png_lossy,
+            png_quality, force_rgb, normalize_contrast, contrast_stretch, rotate_exif_only, colors, dither,
+            no_dither, resize_filter, trim, trim_fuzz, strip_gps, gifsicle, gif_optimize_level, gif_lossy,
+            animated_gif, sizes, rotate, auto_rotate_landscape, split_spreads, split_order, reading_direction,
+            max_aspect_ratio, aspect_policy, sniff, copy_only, page_range, page_range_others, drop_empty,
+            empty_policy, pdf_dpi, gm_arg, gm_env,
+            source: _, target: _, output_mime: _, many: _, merge: _, merge_sources: _, from_list: _,
+            no_plan: _, plan_limit: _, limit_n: _, plan_json: _, yes: _, progress_file: _, explain_glob: _,
+            trace: _, fail_fast: _, min_free_space: _, preserve_mtime: _, no_repack: _, since: _,
+            since_date: _, max_gm_processes: _, jobs: _, compression: _, streamable: _, smart_compression: _,
+            image_timeout: _, no_verify_crc: _, images_only: _,
+            no_default_excludes: _, marker: _, embed_report: _, embed_report_name: _, embed_report_in_dir: _,
+            reprocess: _, warn_mixed_formats: _, fail_on_empty: _, keep_temp: _, debug_image: _,
+            target_template: _, contact_sheet: _, contact_sheet_columns: _, contact_sheet_size: _,
+            max_entries: _, max_uncompressed: _, normalize_names: _, lowercase_names: _, sanitize_names: _,
+            safe_chars: _, baseline: _, manifest: _, manifest_entries: _, report: _, overwrite: _, update: _,
+            content_cache: _, split_size: _, deterministic: _, quality_map: _, print_config: _,
+            hardlink_duplicates: _, log_file: _, log_file_level: _, log_file_rotate: _, drop_comments: _,
+            max_depth: _, depth: _, compare: _, sample: _, measure: _, min_ssim: _, list_formats: _, glob: _,
+            out_dir: _, watch: _, watch_interval: _, watch_done: _,
+        } = opts;
+        ImageOptions {
+            quality, geometry, max_width, max_height, fit, crop_to, crop_gravity, resize_threshold,
+            min_dimension, cover_geometry, cover_quality, aggressive_above_percentile, aggressive_quality,
+            quality_for, format_quality, target_ssim, target_ssim_iterations, extension, normalize_format,
+            define, subsampling, effort, avif_encoder, avif_threads, set_dpi, strip_dpi, bit_depth, png_lossy,
+            png_quality, force_rgb, normalize_contrast, contrast_stretch, rotate_exif_only, colors, dither,
+            no_dither, resize_filter, trim, trim_fuzz, strip_gps, gifsicle, gif_optimize_level, gif_lossy,
+            animated_gif, sizes, rotate, auto_rotate_landscape, split_spreads, split_order, reading_direction,
+            max_aspect_ratio, aspect_policy, sniff, copy_only, page_range, page_range_others, drop_empty,
+            empty_policy, pdf_dpi, gm_arg, gm_env,
+            pages: page_options.iter().collect(),
         }
-        Some(true) => {
-            let temp_name = processed_dir.path().file_name()
-                    .map(|p| p.to_owned());
-            let dest_path = target_zip.into_owned();
-            let dest_path = dest_path.file_stem();
-            let persisted = processed_dir.into_path();
-            let copy_opt = fs_extra::dir::CopyOptions::new();
-            fs_extra::move_items(&vec![persisted], ".", &copy_opt)?;
-            match (temp_name, dest_path) {
-                (Some(old), Some(new)) => Ok(std::fs::rename(old, new)?),
-                _ => { Ok(()) }
+    }
+}
+
+/// Fingerprint of everything that changes how an image is converted, a cached output
+/// being only valid for the options that produced it.
+fn image_options_hash(opts: &Opts, page_options: &HashMap<String, PageOptions>) -> Result<String> {
+    Ok(format!("{:x}", Sha256::digest(serde_json::to_vec(&ImageOptions::new(opts, page_options))?)))
+}
+
+/// The previous version of the target, for --update and --content-cache.
+struct UpdateBase {
+    archive: Mutex<zip::ZipArchive<std::fs::File>>,
+    /// entry name and modification time of every file in it
+    entries: Vec<(String, Option<zip::DateTime>)>,
+    /// modification time of every unpacked source file
+    source_modified: HashMap<PathBuf, zip::DateTime>,
+    /// its sidecar, with --content-cache
+    cache: Option<ContentCache>,
+}
+
+impl UpdateBase {
+    fn open(previous: &Path, metadata: &ArchiveMetadata, cache: Option<ContentCache>) -> Result<UpdateBase> {
+        let mut archive = zip::ZipArchive::new(std::fs::File::open(previous)?)?;
+        let mut entries = vec![];
+        for index in 0..archive.len() {
+            let entry = archive.by_index(index)?;
+            if !entry.is_dir() {
+                entries.push((entry.name().to_string(), entry.last_modified()));
             }
         }
-    };
-    result
-}
+        Ok(UpdateBase { archive: Mutex::new(archive), entries, source_modified: metadata.modified.clone(), cache })
+    }
 
-fn unpack_archive(zip_path: &Path, tmp_dir: &tempfile::TempDir) -> Result<()> {
-    let zip_file = std::fs::File::open(&zip_path)?;
-    let mut archive = zip::ZipArchive::new(zip_file)?;
-    for i in 0..archive.len() {
-        let mut file = archive.by_index(i)?;
-        let out_path = match file.enclosed_name() {
-            Some(path) => path.to_owned(),
-            None => return Err(anyhow!("invalid name for file in archive: {:?}", file.mangled_name())),
+    /// Extracts the entries previously produced from `item` (its conversion, split pieces and sizes, or
+    /// itself when it was kept as it is) to `target` if they are all at least as recent as it,
+    /// returning None when it must be converted.
+    fn reuse(&self, item: &Path, source: &Path, target: &Path, opts: &Opts) -> Result<Option<Vec<PathBuf>>> {
+        let sub_path = item.strip_prefix(source)?;
+        let Some(source_modified) = self.source_modified.get(sub_path) else { return Ok(None) };
+        let parent = sub_path.parent().map(entry_name).filter(|parent| !parent.is_empty());
+        let stem = sub_path.file_stem().map_or_else(String::new, |stem| stem.to_string_lossy().to_string());
+        // only the names the options in effect produce, a sibling `p1-1.jpg` isn't an output of `p1.jpg`
+        let pieces = if opts.split_spreads { "(-[12])?" } else { "" };
+        let sizes = match opts.sizes.as_slice() {
+            [] => String::new(),
+            sizes => format!("@({})", sizes.iter().map(u32::to_string).collect::<Vec<_>>().join("|")),
         };
-        let full_out_path = tmp_dir.path().join(&out_path);
-        debug!("unpack {:?} to {:?}", out_path, full_out_path);
+        let produced = Regex::new(&format!(r"^{}{}{}{}\.{}$",
+            parent.map_or_else(String::new, |parent| format!("{}/", escape(&parent))),
+            escape(&stem), pieces, sizes, escape(output_extension(opts))))?;
+        let mut candidates: Vec<_> = self.entries.iter().filter(|(name, _)| produced.is_match(name)).collect();
+        if candidates.is_empty() {
+            // copied unchanged, optimized by gifsicle or quantized as PNG, the output kept the source's name
+            let kept = entry_name(sub_path);
+            candidates = self.entries.iter().filter(|(name, _)| *name == kept).collect();
+        }
+        if candidates.is_empty() || candidates.iter().any(|(_, modified)| modified.is_none_or(|modified| modified < *source_modified)) {
+            return Ok(None);
+        }
+        self.extract(candidates.iter().map(|(name, _)| name.as_str()), target).map(Some)
+    }
 
-        if (&*file.name()).ends_with('/') {
-            debug!("create dir {:?}", full_out_path);
-            std::fs::create_dir_all(full_out_path)?;
-        } else {
-            create_parent(&full_out_path)?;
-            let mut out_file = std::fs::File::create(full_out_path)?;
-            std::io::copy(&mut file, &mut out_file)?;
+    /// Extracts the outputs the sidecar lists for `sub_path` when its content is still `sha256`.
+    fn reuse_cached(&self, sub_path: &Path, sha256: &str, target: &Path) -> Result<Option<Vec<PathBuf>>> {
+        let Some(cached) = self.cache.as_ref().and_then(|cache| cache.entries.get(&entry_name(sub_path))) else {
+            return Ok(None);
+        };
+        let all_present = cached.outputs.iter().all(|output| self.entries.iter().any(|(name, _)| name == output));
+        if cached.sha256 != sha256 || cached.outputs.is_empty() || !all_present {
+            return Ok(None);
         }
-                // Get and Set permissions
-        #[cfg(unix)]
-        {
-            use std::os::unix::fs::PermissionsExt;
+        self.extract(cached.outputs.iter().map(String::as_str), target).map(Some)
+    }
 
-            if let Some(mode) = file.unix_mode() {
-                std::fs::set_permissions(tmp_dir.path().join(&out_path), std::fs::Permissions::from_mode(mode))?;
-            }
+    fn extract<'a>(&self, names: impl Iterator<Item = &'a str>, target: &Path) -> Result<Vec<PathBuf>> {
+        let mut archive = self.archive.lock().unwrap();
+        let mut extracted = vec![];
+        for name in names {
+            let destination = target.join(name);
+            create_parent(&destination)?;
+            std::io::copy(&mut archive.by_name(name)?, &mut std::fs::File::create(&destination)?)?;
+            extracted.push(destination);
         }
+        Ok(extracted)
     }
-    Ok(())
 }
 
-fn process_files(source: &dyn AsRef<Path>, target: &Path, opts: &Opts) -> Result<()> {
-    let walker = globwalk::GlobWalkerBuilder::from_patterns(
-        source,
-        &[ "**/*" ],
-    )
-    .file_type(GlobFileType::FILE | GlobFileType::DIR)
-    .contents_first(false) // directory before content
-    .build()?
-    .into_iter()
-    .filter_map(Result::ok);
+/// What happened to the files of one archive.
+#[derive(Default, Debug)]
+struct ArchiveStats {
+    /// image files found, whatever happened to them
+    found: usize,
+    images: usize,
+    outputs: usize,
+    copied: usize,
+    failed: usize,
+    transcoded: usize,
+    nested: usize,
+    dropped: usize,
+    /// images outside --page-range left out of the output
+    outside_range: usize,
+    /// files matching DEFAULT_EXCLUDES, left out of the output
+    junk: usize,
+    /// zero-length files dropped by --drop-empty, or without it zero-length images copied or failed
+    empty: usize,
+    /// files added to the output by img-optim itself (the --marker entry)
+    own_files: usize,
+    reused: usize,
+    /// input files that produced nothing because their processing failed
+    failures: Vec<PathBuf>,
+    /// source image and the files produced from it, in processing order
+    conversions: Vec<(PathBuf, Vec<PathBuf>)>,
+    /// --measure result of each produced file
+    measurements: Vec<(PathBuf, f64)>,
+    /// --report records, in processing order
+    reports: Vec<FileReport>,
+    /// --content-cache records of the images converted or reused, by source entry
+    cached: HashMap<String, CachedImage>,
+}
 
-    for entry in walker {
-        let entry_type = entry.file_type();
-        debug!("{:?} type {:?}", entry, entry_type);
-        if entry_type.is_dir() {
-            // create dir in destination
-            let path = entry.path();
-            match path.absolutize() {
-                Ok(canon) => {
-                    info!("create directory {:?}", canon);
-                    create_dir_all(canon)?
-                },
-                Err(error) => {
-                    warn!("couldn't absolutize path {:?} - {:?}", entry, error);
-                }
+impl ArchiveStats {
+    /// Adds the stats of another worker; lists are sorted back into page order.
+    fn merge(&mut self, other: ArchiveStats) {
+        self.found += other.found;
+        self.images += other.images;
+        self.outputs += other.outputs;
+        self.copied += other.copied;
+        self.failed += other.failed;
+        self.transcoded += other.transcoded;
+        self.nested += other.nested;
+        self.dropped += other.dropped;
+        self.outside_range += other.outside_range;
+        self.junk += other.junk;
+        self.empty += other.empty;
+        self.reused += other.reused;
+        self.failures.extend(other.failures);
+        self.failures.sort();
+        self.conversions.extend(other.conversions);
+        self.conversions.sort_by(|a, b| a.0.cmp(&b.0));
+        self.measurements.extend(other.measurements);
+        self.measurements.sort_by(|a, b| a.0.cmp(&b.0));
+        self.reports.extend(other.reports);
+        self.reports.sort_by(|a, b| a.file.cmp(&b.file));
+        self.cached.extend(other.cached);
+    }
+
+    /// Follows the --sanitize-names `renames` of the files below `dir`, so that the reports
+    /// name the entries of the output.
+    fn rename_outputs(&mut self, dir: &Path, renames: &HashMap<PathBuf, PathBuf>) {
+        let rename = |output: &mut PathBuf| {
+            if let Some(renamed) = output.strip_prefix(dir).ok().and_then(|sub_path| renames.get(sub_path)) {
+                *output = dir.join(renamed);
             }
-        } else if entry_type.is_file() {
-            // process file
-            let path = entry.path();
-            match path.absolutize() {
-                Ok(canon) => match process_one_file(&canon, source.as_ref(), target, opts) {
-                    Ok(_) => {}
-                    Err(error) => {
-                        error!("{}", error);
-                        // continue with other files
-                    }
+        };
+        for (_, produced) in &mut self.conversions {
+            produced.iter_mut().for_each(rename);
+        }
+        for (output, _) in &mut self.measurements {
+            rename(output);
+        }
+        for report in &mut self.reports {
+            for output in &mut report.outputs {
+                if let Some(renamed) = renames.get(output) {
+                    *output = renamed.clone();
                 }
-                Err(error) => {
-                    warn!("couldn't absolutize path {:?} - {:?}", entry, error);
+            }
+        }
+        for cached in self.cached.values_mut() {
+            for output in &mut cached.outputs {
+                if let Some(renamed) = renames.get(Path::new(output.as_str())) {
+                    *output = entry_name(renamed);
                 }
             }
-        } else {
-            println!("{:?} is not a file or directory, skipping", entry);
         }
-
     }
-    Ok(())
 }
 
-lazy_static! {
-    static ref IMAGE_EXTENSIONS: Vec<&'static str> = vec!["jpg","jpeg", "png", "webp", "avif", "gif"];
+/// One `--report` line.
+#[derive(Debug, Serialize)]
+struct FileReport {
+    file: PathBuf,
+    outputs: Vec<PathBuf>,
+    duration_ms: u128,
+    backend: &'static str,
 }
 
-fn process_one_file(item: &Path, source: &Path, target: &Path, opts: &Opts) -> Result<()> {
-    let extension = item.extension()
-                .map_or_else(
-                    || "".to_string(),
-                    |ext| ext.to_str().unwrap_or("").to_string()
-                );
-    if IMAGE_EXTENSIONS.contains(&extension.as_str().to_lowercase().as_str()) {
-        process_one_image(item, source, target, opts)
-    } else {
-        let sub_path = item.strip_prefix(source)?;
-        let destination = target.join(sub_path);
-        let _ = std::fs::copy(item, destination);
-        Ok(())
+fn main() -> Result<()> {
+    let mut opts: Opts = Opts::parse();
+    if let Some(mime) = &opts.output_mime {
+        opts.extension = Some(mime_extension(mime)?.to_string());
+    }
+    apply_env_defaults(&mut opts);
+    if opts.merge {
+        if !opts.target.is_empty() {
+            bail!("--merge takes its sources from --source and --from-list, give only the target");
+        }
+        opts.target = std::mem::take(&mut opts.source);
+        opts.source = merge_sources(&opts)?.join("+");
+    }
+    init_logging(&opts)?;
+
+    if let Some(max) = opts.max_gm_processes {
+        if max == 0 {
+            bail!("--max-gm-processes must be at least 1");
+        }
+        GM_PROCESSES.set_limit(max);
+    }
+    if opts.list_formats {
+        return list_formats(&opts);
+    }
+    if !opts.gm_env.is_empty() {
+        debug!("gm environment: {}", opts.gm_env.join(" "));
+    }
+    if opts.png_lossy && Command::new("pngquant").arg("--version").output().is_err() {
+        bail!("--png-lossy needs `pngquant`, which couldn't be run; install it or drop the option");
+    }
+    if opts.gifsicle && Command::new("gifsicle").arg("--version").output().is_err() {
+        bail!("--gifsicle needs `gifsicle`, which couldn't be run; install it or drop the option");
+    }
+    if opts.rotate_exif_only && Command::new("jpegtran").arg("-version").output().is_err() {
+        bail!("--rotate-exif-only needs `jpegtran`, which couldn't be run; install it or drop the option");
+    }
+    if let Some(template) = &opts.contact_sheet {
+        validate_target_template(template).map_err(|error| anyhow!("--contact-sheet: {}", error))?;
+    }
+    if !opts.copy_only {
+        if let Some(extension) = opts.normalize_format.as_deref().or(opts.extension.as_deref()) {
+            check_output_extension(extension, &opts)?;
+        }
+        if let Some(depth) = opts.bit_depth {
+            check_depth(depth, output_extension(&opts))?;
+        }
+        check_fit(&opts)?;
+        check_crop_gravity(&opts)?;
+        if opts.avif_encoder.is_some() || opts.avif_threads.is_some() {
+            check_avif_encoder(&opts)?;
+        }
+    }
+    if opts.print_config {
+        println!("{}", serde_json::to_string_pretty(&effective_config(&opts))?);
+        return Ok(());
+    }
+    if let Some(manifest) = &opts.manifest {
+        // start a fresh manifest for this run, archives are then appended one by one
+        std::fs::File::create(manifest)?;
+    }
+    if let Some(report) = &opts.report {
+        std::fs::File::create(report)?;
+    }
+    if opts.trace.is_some() {
+        TRACER.enable();
     }
+    let result = process_all(&opts);
+    if let Some(trace) = &opts.trace {
+        TRACER.write(Path::new(trace))?;
+    }
+    result
 }
 
-fn process_one_image(item: &Path, source: &Path, target: &Path, opts: &Opts) -> Result<()> {
-    let sub_path = item.strip_prefix(source)?;
+/// Runs what the options ask for: a --glob, a --many batch or a single archive.
+fn process_all(opts: &Opts) -> Result<()> {
+    if let (Some(pattern), Some(out_dir)) = (&opts.glob, &opts.out_dir) {
+        return process_glob(pattern, Path::new(out_dir), opts);
+    }
+    if let (Some(watched), Some(out_dir)) = (&opts.watch, &opts.out_dir) {
+        return watch_dir(Path::new(watched), Path::new(out_dir), opts);
+    }
+    if opts.out_dir.is_some() {
+        bail!("--out-dir goes with --glob or --watch");
+    }
+    match &opts.many {
+        Some(pattern) if opts.explain_glob => explain_pattern(opts, pattern),
+        Some(pattern) => {
+            let parts = resolve_pattern(opts, pattern)?;
+            let mut parts = filter_since(opts, parts)?;
+            let omitted = match opts.limit_n {
+                Some(limit) if parts.len() > limit => parts.split_off(limit).len(),
+                _ => 0,
+            };
+            if let Some(plan_file) = &opts.plan_json {
+                return write_plan_json(plan_file, &parts);
+            }
+            let shown = if opts.no_plan { 0 } else { opts.plan_limit.unwrap_or(parts.len()) };
+            for part in parts.iter().take(shown) {
+                println!("{} => {}", part.source, part.target);
+            }
+            if shown == 0 {
+                println!("{} archives to process", parts.len());
+            } else if parts.len() > shown {
+                println!("...and {} more", parts.len() - shown);
+            }
+            if omitted > 0 {
+                println!("{} more archives matched, left out by --limit-n", omitted);
+            }
 
-    let result = target.join(sub_path)
-        .with_extension(&opts.extension.as_deref()
-        .unwrap_or("jpg"));
-    create_parent(&result)?;
+            if opts.yes || Confirm::new().with_prompt("Do you want to continue?").interact()? {
+                println!("Processing archives...");
+                let started = Instant::now();
+                let mut failed = vec![];
+                for (done, part) in parts.iter().enumerate() {
+                    report_progress(opts, &BatchProgress::new(started, done, parts.len(), Some(&part.source)))?;
+                    if let Err(error) = process_archive(part) {
+                        if opts.fail_fast {
+                            return Err(error);
+                        }
+                        error!("{} failed: {:#}", part.source, error);
+                        failed.push(part.source.clone());
+                    }
+                }
+                report_progress(opts, &BatchProgress::new(started, parts.len(), parts.len(), None))?;
+                if !failed.is_empty() {
+                    for source in &failed {
+                        error!("failed: {}", source);
+                    }
+                    bail!("{} of {} archives failed", failed.len(), parts.len());
+                }
+            }
+            Ok(())
+        },
+        None => process_archive(opts),
+    }
+}
 
-    let mut args: Vec<String> = vec![
-        "convert".to_string(), item.as_os_str().to_str().unwrap().to_string(),
-        "-geometry".to_string(), opts.geometry.as_deref().unwrap_or("1000x1400^").to_string(),
-        "-quality".to_string(), opts.quality.as_deref().unwrap_or("80").to_string(),
-    ];
+/// The --trace recorder, collecting complete ("X") events of the Chrome tracing format.
+struct Tracer {
+    /// time origin of the events, which are in microseconds
+    started: Instant,
+    events: Mutex<Option<Vec<serde_json::Value>>>,
+}
 
-    if let Some(define) = &opts.define {
-        args.push("define".to_string());
-        args.push(define.to_string());
+lazy_static! {
+    static ref TRACER: Tracer = Tracer { started: Instant::now(), events: Mutex::new(None) };
+}
+
+thread_local! {
+    /// small per-thread number, what trace viewers show as the track
+    static TRACE_THREAD: usize = {
+        static NEXT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(1);
+        NEXT.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+    };
+}
+
+impl Tracer {
+    fn enable(&self) {
+        *self.events.lock().unwrap() = Some(vec![]);
     }
-    args.push(result.to_str().unwrap().to_string());
 
-    let mut command = Command::new("gm");
-    command.args(args);
-    debug!("Command: {:?}", command);
+    /// Starts a span recorded when dropped, or nothing without --trace.
+    fn span(&self, category: &'static str, name: &str, detail: &str) -> Option<Span> {
+        self.events.lock().unwrap().is_some().then(|| Span {
+            category,
+            name: name.to_string(),
+            detail: detail.to_string(),
+            started: Instant::now(),
+        })
+    }
 
-    let output = command.output()?;
-    if output.status.success() {
+    fn write(&self, path: &Path) -> Result<()> {
+        let events = self.events.lock().unwrap().take().unwrap_or_default();
+        let trace = serde_json::json!({ "traceEvents": events, "displayTimeUnit": "ms" });
+        let content = serde_json::to_string(&trace)?;
+        write_atomically(path, |partial| Ok(std::fs::write(partial, &content)?))?;
+        info!("trace of {} spans written to {:?}", trace["traceEvents"].as_array().map_or(0, Vec::len), path);
         Ok(())
-    } else {
-        let error = format!("`gm convert` invocation failed\n{}\n",
-        String::from_utf8_lossy(&output.stderr));
-        Err(anyhow!(error))
     }
 }
 
-fn repack_output(dir: &tempfile::TempDir, zip: &Path) -> Result<()> {
-    let zip_path= zip.to_str().unwrap();
-    let mut command = Command::new("zip");
-    command.args(vec![
-        "--recurse-paths",
-        zip_path, // zip file
-        "." // what to add
-    ]);
-    command.current_dir(dir);
+struct Span {
+    category: &'static str,
+    name: String,
+    /// archive or file the span worked on
+    detail: String,
+    started: Instant,
+}
 
-    let output = command.output()?;
-    if output.status.success() {
-        Ok(())
-    } else {
-        let error = format!("`zip` invocation failed\n{}\n",
-                                    String::from_utf8_lossy(&output.stderr));
-        Err(anyhow!(error))
+impl Drop for Span {
+    fn drop(&mut self) {
+        let event = serde_json::json!({
+            "name": self.name, "cat": self.category, "ph": "X",
+            "ts": self.started.duration_since(TRACER.started).as_micros() as u64,
+            "dur": self.started.elapsed().as_micros() as u64,
+            "pid": std::process::id(), "tid": TRACE_THREAD.with(|thread| *thread),
+            "args": { "detail": self.detail },
+        });
+        if let Some(events) = TRACER.events.lock().unwrap().as_mut() {
+            events.push(event);
+        }
     }
 }
 
-fn create_parent(file_path: &Path) -> Result<()> {
+/// The --plan-json output: `[{"source": "...", "target": "...", "options": {<effective options>}}, ...]`.
+fn write_plan_json(plan_file: &str, parts: &[Opts]) -> Result<()> {
+    let plan: Vec<_> = parts.iter()
+        .map(|part| serde_json::json!({ "source": part.source, "target": part.target, "options": effective_config(part) }))
+        .collect();
+    let content = serde_json::to_string_pretty(&plan)?;
+    if plan_file == "-" {
+        println!("{}", content);
+    } else {
+        let content = content + "\n";
+        write_atomically(Path::new(plan_file), |partial| Ok(std::fs::write(partial, &content)?))?;
+        info!("plan of {} archives written to {}", parts.len(), plan_file);
+    }
+    Ok(())
+}
+
+/// Where a --many batch stands, what --progress-file holds.
+#[derive(Serialize)]
+struct BatchProgress {
+    done: usize,
+    total: usize,
+    current: Option<String>,
+    elapsed_secs: u64,
+    /// mean time per archive so far, unknown until the first one is done
+    average_secs: Option<u64>,
+    eta_secs: Option<u64>,
+}
+
+impl BatchProgress {
+    fn new(started: Instant, done: usize, total: usize, current: Option<&str>) -> BatchProgress {
+        let elapsed = started.elapsed();
+        let average = (done > 0).then(|| elapsed / done as u32);
+        BatchProgress {
+            done,
+            total,
+            current: current.map(str::to_string),
+            elapsed_secs: elapsed.as_secs(),
+            average_secs: average.map(|average| average.as_secs()),
+            eta_secs: average.map(|average| (average * (total - done) as u32).as_secs()),
+        }
+    }
+}
+
+fn report_progress(opts: &Opts, progress: &BatchProgress) -> Result<()> {
+    if let Some(eta) = progress.eta_secs {
+        info!("{}/{} archives done in {}, ETA {}", progress.done, progress.total,
+            humantime::format_duration(Duration::from_secs(progress.elapsed_secs)),
+            humantime::format_duration(Duration::from_secs(eta)));
+    }
+    if let Some(progress_file) = &opts.progress_file {
+        let content = serde_json::to_string(progress)?;
+        write_atomically(Path::new(progress_file), |partial| Ok(std::fs::write(partial, &content)?))?;
+    }
+    Ok(())
+}
+
+/// Logger sending everything to the console logger, and to a file above its own level.
+struct TeeLogger {
+    console: env_logger::Logger,
+    file: Mutex<std::fs::File>,
+    file_level: LevelFilter,
+}
+
+impl Log for TeeLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.console.enabled(metadata) || metadata.level() <= self.file_level
+    }
+
+    fn log(&self, record: &Record) {
+        self.console.log(record);
+        if record.level() <= self.file_level {
+            let mut file = self.file.lock().unwrap();
+            let _ = writeln!(file, "[{} {:5} {}] {}",
+                humantime::format_rfc3339_seconds(SystemTime::now()), record.level(), record.target(), record.args());
+        }
+    }
+
+    fn flush(&self) {
+        self.console.flush();
+        let _ = self.file.lock().unwrap().flush();
+    }
+}
+
+fn init_logging(opts: &Opts) -> Result<()> {
+    // stdout carries the archive when the target is `-`, or the plan with `--plan-json -`
+    let console_target = if opts.target == "-" || opts.plan_json.as_deref() == Some("-") { Target::Stderr } else { Target::Stdout };
+    let console = env_logger::Builder::from_env(Env::default().default_filter_or("debug"))
+                            .target(console_target)
+                            .build();
+    let log_file = match &opts.log_file {
+        Some(log_file) => log_file,
+        None => {
+            log::set_max_level(console.filter());
+            log::set_boxed_logger(Box::new(console))?;
+            return Ok(());
+        }
+    };
+    if opts.log_file_rotate && Path::new(log_file).exists() {
+        std::fs::rename(log_file, format!("{}.1", log_file))?;
+    }
+    let file = std::fs::OpenOptions::new().create(true).append(true).open(log_file)?;
+    log::set_max_level(console.filter().max(opts.log_file_level));
+    log::set_boxed_logger(Box::new(TeeLogger { console, file: Mutex::new(file), file_level: opts.log_file_level }))?;
+    Ok(())
+}
+
+/// Extensions img-optim may be asked to produce, and the gm format writing each of them.
+const OUTPUT_FORMATS: [(&str, &str); 8] = [
+    ("jpg", "JPEG"), ("jpeg", "JPEG"), ("png", "PNG"), ("webp", "WEBP"),
+    ("avif", "AVIF"), ("gif", "GIF"), ("jxl", "JXL"), ("tiff", "TIFF"),
+];
+
+/// Extension written for each --output-mime type, `image/jpg` and `image/x-tiff` being common aliases.
+const OUTPUT_MIME_TYPES: [(&str, &str); 9] = [
+    ("image/jpeg", "jpg"), ("image/jpg", "jpg"), ("image/png", "png"), ("image/webp", "webp"),
+    ("image/avif", "avif"), ("image/gif", "gif"), ("image/jxl", "jxl"), ("image/tiff", "tiff"), ("image/x-tiff", "tiff"),
+];
+
+/// Bits per channel each output extension can hold, for --depth.
+const OUTPUT_DEPTHS: [(&str, &[u8]); 8] = [
+    ("jpg", &[8]), ("jpeg", &[8]), ("png", &[8, 16]), ("webp", &[8]),
+    ("avif", &[8]), ("gif", &[8]), ("jxl", &[8, 16]), ("tiff", &[8, 16]),
+];
+
+lazy_static! {
+    static ref GM_FORMATS: Mutex<Option<Vec<GmFormat>>> = Mutex::new(None);
+    // `      JPEG* S  rw-  Joint Photographic...`: name (`*` when it supports blobs), coder class, mode
+    static ref GM_FORMAT_LINE: Regex = Regex::new(r"^\s*([A-Za-z0-9][\w-]*)\*?\s+(?:[PSU]\s+)?([r-])([w-])([+-])(?:\s|$)").unwrap();
+}
+
+/// Formats supported by the installed gm, queried once per run.
+fn gm_formats(opts: &Opts) -> Result<Vec<GmFormat>> {
+    let mut cache = GM_FORMATS.lock().unwrap();
+    if let Some(formats) = &*cache {
+        return Ok(formats.clone());
+    }
+    let output = run_gm(gm_command(opts).args(["convert", "-list", "format"]), None)
+        .map_err(|error| anyhow!("couldn't run gm to list its formats: {}", error))?;
+    if !output.status.success() {
+        bail!("`gm convert -list format` invocation failed\n{}\n", String::from_utf8_lossy(&output.stderr));
+    }
+    let formats = parse_gm_formats(&String::from_utf8_lossy(&output.stdout));
+    if formats.is_empty() {
+        bail!("no format found in the `gm convert -list format` output, its layout may have changed");
+    }
+    debug!("gm supports {} formats", formats.len());
+    *cache = Some(formats.clone());
+    Ok(formats)
+}
+
+/// The formats of a `gm convert -list format` listing; the header and description lines match none.
+fn parse_gm_formats(listing: &str) -> Vec<GmFormat> {
+    listing.lines()
+        .filter_map(|line| GM_FORMAT_LINE.captures(line))
+        .map(|captures| GmFormat {
+            name: captures[1].to_uppercase(),
+            readable: &captures[2] == "r",
+            writable: &captures[3] == "w",
+        })
+        .collect()
+}
+
+/// Output extensions usable with this gm build.
+fn writable_extensions(opts: &Opts) -> Result<Vec<&'static str>> {
+    let formats = gm_formats(opts)?;
+    Ok(OUTPUT_FORMATS.iter()
+        .filter(|(_, format)| formats.iter().any(|gm_format| gm_format.writable && gm_format.name == *format))
+        .map(|(extension, _)| *extension)
+        .collect())
+}
+
+/// The extension of an --output-mime type; parameters (`; charset=...`) and case are ignored.
+fn mime_extension(mime: &str) -> Result<&'static str> {
+    let essence = mime.split(';').next().unwrap_or_default().trim().to_lowercase();
+    match OUTPUT_MIME_TYPES.iter().find(|(known, _)| *known == essence) {
+        Some((_, extension)) => Ok(extension),
+        None => {
+            let known: Vec<_> = OUTPUT_MIME_TYPES.iter().map(|(known, _)| *known).collect();
+            bail!("`{}` is not a MIME type img-optim can write, expected one of: {}", mime, known.join(", "))
+        }
+    }
+}
+
+/// Fails early on an output extension gm can't write (`--extension cbz`), listing the usable ones.
+fn check_output_extension(extension: &str, opts: &Opts) -> Result<()> {
+    let extension = extension.trim_start_matches('.').to_lowercase();
+    if !OUTPUT_FORMATS.iter().any(|(known, _)| *known == extension) {
+        let known: Vec<_> = OUTPUT_FORMATS.iter().map(|(known, _)| *known).collect();
+        bail!("`{}` is not an image format img-optim can write, expected one of: {}", extension, known.join(", "));
+    }
+    match writable_extensions(opts) {
+        Ok(writable) if !writable.contains(&extension.as_str()) => {
+            bail!("the installed gm can't write `{}`, usable extensions: {}", extension, writable.join(", "))
+        }
+        Ok(_) => Ok(()),
+        Err(error) => {
+            warn!("couldn't check that gm can write `{}`: {}", extension, error);
+            Ok(())
+        }
+    }
+}
+
+/// Fails early on a --depth the output format can't hold (`--depth 16` with JPEG).
+fn check_depth(depth: u8, extension: &str) -> Result<()> {
+    let extension = extension.trim_start_matches('.').to_lowercase();
+    match OUTPUT_DEPTHS.iter().find(|(known, _)| *known == extension) {
+        Some((_, depths)) if !depths.contains(&depth) => {
+            let depths: Vec<_> = depths.iter().map(u8::to_string).collect();
+            bail!("`{}` output can't have a depth of {} bits, supported: {}", extension, depth, depths.join(", "))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Fails early on an --avif-encoder the installed libavif wasn't built with, as the encoders
+/// `avifenc --version` lists (`aom [enc/dec]:3.6.1, rav1e [enc]:0.6.6`); gm itself doesn't tell.
+fn check_avif_encoder(opts: &Opts) -> Result<()> {
+    if !output_extension(opts).eq_ignore_ascii_case("avif") {
+        warn!("--avif-encoder and --avif-threads only apply to AVIF output, not {}", output_extension(opts));
+        return Ok(());
+    }
+    let threads = opts.avif_threads.map_or_else(|| "default".to_string(), |threads| threads.to_string());
+    let Some(encoder) = &opts.avif_encoder else {
+        info!("AVIF encoding with libavif's default encoder, {} threads", threads);
+        return Ok(());
+    };
+    let encoders = match Command::new("avifenc").arg("--version").output() {
+        Ok(output) => {
+            let version = String::from_utf8_lossy(&output.stdout).to_string() + &String::from_utf8_lossy(&output.stderr);
+            Regex::new(r"(\w+) \[enc")?.captures_iter(&version).map(|captures| captures[1].to_string()).collect::<Vec<_>>()
+        }
+        Err(error) => {
+            warn!("couldn't run avifenc to check that libavif has the {} encoder: {}", encoder, error);
+            info!("AVIF encoding with {} (unchecked), {} threads", encoder, threads);
+            return Ok(());
+        }
+    };
+    if !encoders.contains(encoder) {
+        bail!("libavif has no {} encoder, available: {}", encoder, if encoders.is_empty() { "none".to_string() } else { encoders.join(", ") });
+    }
+    info!("AVIF encoding with {}, {} threads", encoder, threads);
+    Ok(())
+}
+
+fn list_formats(opts: &Opts) -> Result<()> {
+    let formats = gm_formats(opts)?;
+    println!("usable output extensions: {}", writable_extensions(opts)?.join(", "));
+    println!();
+    println!("all gm formats (r = readable, w = writable):");
+    for format in &formats {
+        println!("  {:10} {}{}", format.name,
+            if format.readable { "r" } else { "-" },
+            if format.writable { "w" } else { "-" });
+    }
+    Ok(())
+}
+
+/// Fills options left off the command line from `IMG_OPTIM_*` variables: a flag always wins
+/// over the environment, including flags it conflicts with (`--max-width` over `IMG_OPTIM_GEOMETRY`),
+/// and the environment over the built-in defaults.
+fn apply_env_defaults(opts: &mut Opts) {
+    let from_env = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+    if opts.quality.is_none() && opts.target_ssim.is_none() {
+        opts.quality = from_env("IMG_OPTIM_QUALITY");
+    }
+    if opts.geometry.is_none() && opts.max_width.is_none() && opts.max_height.is_none() {
+        opts.geometry = from_env("IMG_OPTIM_GEOMETRY");
+    }
+    if opts.extension.is_none() {
+        opts.extension = from_env("IMG_OPTIM_EXTENSION");
+    }
+}
+
+/// Options as they will actually be used, with the built-in defaults filled in.
+fn effective_config(opts: &Opts) -> Opts {
+    Opts {
+        geometry: Some(geometry(opts)),
+        quality: Some(opts.quality.clone().unwrap_or_else(|| default_quality(opts))),
+        extension: Some(output_extension(opts).to_string()),
+        no_repack: Some(opts.no_repack.unwrap_or(false)),
+        ..opts.clone()
+    }
+}
+
+/// The glob listing the --many sources and the regex capturing the token of each, the `pattern`
+/// part of the source name becoming `?`s in the first and a group of as many characters in the second.
+fn many_glob_and_regex(opts: &Opts, pattern: &str) -> Result<(String, Regex)> {
+    if !opts.source.contains(pattern) {
+        bail!("source name {} doesn't contain pattern {}",opts.source, pattern);
+    }
+    let pattern_len = pattern.len();
+    let glob = escape_glob(&opts.source).replace(pattern, &"?".repeat(pattern_len));
+    debug!("using glob '{}'", glob);
+    let regex_pattern = escape(&opts.source).replace(pattern, &format!("(.{{{}}})", pattern_len));
+    debug!("using pattern '{}'", regex_pattern);
+    Ok((glob, Regex::new(&regex_pattern)?))
+}
+
+fn many_walker(glob_pattern: String) -> Result<impl Iterator<Item = globwalk::DirEntry>> {
+    Ok(glob_builder(glob_pattern)
+        .file_type(GlobFileType::FILE )
+        .sort_by(|a, b| a.path().to_str().unwrap().cmp(b.path().to_str().unwrap()))
+        .build()?
+        .filter_map(Result::ok))
+}
+
+/// --explain-glob: shows how the --many pattern is resolved instead of processing anything.
+fn explain_pattern(opts: &Opts, pattern: &str) -> Result<()> {
+    let (glob_pattern, source_regex) = many_glob_and_regex(opts, pattern)?;
+    println!("glob:  {}", glob_pattern);
+    println!("regex: {}", source_regex.as_str());
+    let mut matched = 0;
+    let mut captured = 0;
+    for entry in many_walker(glob_pattern)? {
+        matched += 1;
+        let path = entry.path().to_string_lossy();
+        match source_regex.captures(&path).and_then(|captures| captures.get(1)) {
+            Some(capture) => {
+                captured += 1;
+                let target = match &opts.target_template {
+                    Some(template) => render_target_template(template, entry.path(), capture.as_str()),
+                    None => opts.target.replace(pattern, capture.as_str()),
+                };
+                println!("  {} => token '{}' => {}", path, capture.as_str(), target);
+            }
+            None => println!("  {} => not captured by the regex", path),
+        }
+    }
+    println!("{} files matched the glob, {} captured by the regex", matched, captured);
+    Ok(())
+}
+
+fn resolve_pattern(opts:&Opts, pattern: &str) -> Result<Vec<Opts>> {
+    let (glob_pattern, source_regex) = many_glob_and_regex(opts, pattern)?;
+
+    if let Some(template) = &opts.target_template {
+        validate_target_template(template)?;
+    } else if !opts.target.contains(pattern) {
+        bail!("target name {} doesn't contain pattern {}",opts.target, pattern);
+    }
+
+    let walker = many_walker(glob_pattern)?;
+
+    let quality_map = match &opts.quality_map {
+        Some(file) => read_quality_map(Path::new(file))?,
+        None => HashMap::new(),
+    };
+
+    let mut result = vec![];
+    for entry in walker {
+        let path = entry.path().as_os_str().to_str().unwrap();
+        debug!("file: {}", path);
+        match source_regex.captures(path) {
+            None => bail!("no idea what happened"),
+            Some(captures) => {
+                let capture = captures.get(1).unwrap();
+                let value = capture.as_str();
+                let target_name = match &opts.target_template {
+                    Some(template) => render_target_template(template, entry.path(), value),
+                    None => opts.target.replace(pattern, value),
+                };
+                let quality = match quality_map.get(value) {
+                    Some(quality) => {
+                        debug!("quality {} for token {}", quality, value);
+                        Some(quality.clone())
+                    }
+                    None => opts.quality.clone(),
+                };
+                result.push(Opts {
+                    source: path.to_string(),
+                    target: target_name,
+                    many: None,
+                    quality,
+                    ..opts.clone()
+                });
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Reads `token=quality` lines, ignoring blank lines and `#` comments.
+fn read_quality_map(file: &Path) -> Result<HashMap<String, String>> {
+    let content = std::fs::read_to_string(file)?;
+    let mut result = HashMap::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once('=') {
+            Some((token, quality)) => {
+                result.insert(token.trim().to_string(), quality.trim().to_string());
+            }
+            None => bail!("{:?} line {}: expected `token=quality`, got '{}'", file, number + 1, line),
+        }
+    }
+    Ok(result)
+}
+
+lazy_static! {
+    static ref TEMPLATE_PLACEHOLDER: Regex = Regex::new(r"\{([^{}]*)\}").unwrap();
+}
+
+const TEMPLATE_VARIABLES: [&str; 4] = ["stem", "parent", "date", "token"];
+
+fn validate_target_template(template: &str) -> Result<()> {
+    for captures in TEMPLATE_PLACEHOLDER.captures_iter(template) {
+        let name = &captures[1];
+        if !TEMPLATE_VARIABLES.contains(&name) {
+            bail!("unknown placeholder {{{}}} in template {:?}, expected one of {:?}", name, template, TEMPLATE_VARIABLES);
+        }
+    }
+    Ok(())
+}
+
+fn render_target_template(template: &str, source: &Path, token: &str) -> String {
+    let stem = source.file_stem().map_or_else(String::new, |stem| stem.to_string_lossy().to_string());
+    let parent = source.parent().map_or_else(String::new, |parent| parent.to_string_lossy().to_string());
+    let date = humantime::format_rfc3339(SystemTime::now()).to_string()[..10].to_string();
+    TEMPLATE_PLACEHOLDER.replace_all(template, |captures: &regex::Captures| {
+        match &captures[1] {
+            "stem" => stem.clone(),
+            "parent" => parent.clone(),
+            "date" => date.clone(),
+            _ => token.to_string(),
+        }
+    }).to_string()
+}
+
+fn since_cutoff(opts: &Opts) -> Result<Option<SystemTime>> {
+    if let Some(since) = &opts.since {
+        let duration = humantime::parse_duration(since)
+            .map_err(|error| anyhow!("invalid --since duration '{}': {}", since, error))?;
+        let cutoff = SystemTime::now().checked_sub(duration)
+            .ok_or_else(|| anyhow!("--since duration '{}' is too large", since))?;
+        Ok(Some(cutoff))
+    } else if let Some(date) = &opts.since_date {
+        // accept a plain date as midnight
+        let full_date = if date.len() == 10 { format!("{} 00:00:00", date) } else { date.to_string() };
+        let cutoff = humantime::parse_rfc3339_weak(&full_date)
+            .map_err(|error| anyhow!("invalid --since-date '{}': {}", date, error))?;
+        Ok(Some(cutoff))
+    } else {
+        Ok(None)
+    }
+}
+
+fn filter_since(opts: &Opts, parts: Vec<Opts>) -> Result<Vec<Opts>> {
+    let cutoff = match since_cutoff(opts)? {
+        Some(cutoff) => cutoff,
+        None => return Ok(parts),
+    };
+    let total = parts.len();
+    let mut result = vec![];
+    for part in parts {
+        let modified = std::fs::metadata(&part.source)?.modified()?;
+        if modified > cutoff {
+            result.push(part);
+        } else {
+            debug!("{} not modified since cutoff, skipping", part.source);
+        }
+    }
+    info!("{} of {} sources filtered out as older than cutoff", total - result.len(), total);
+    Ok(result)
+}
+
+/// Optimizes loose image files, `out_dir` mirroring the tree below the pattern's
+/// leading directories (`photos/**/*.jpg` writes `photos/a/b.jpg` to `out_dir/a/b.jpg`).
+fn process_glob(pattern: &str, out_dir: &Path, opts: &Opts) -> Result<()> {
+    let is_literal = |component: &std::path::Component| {
+        !component.as_os_str().to_string_lossy().contains(['*', '?', '[', '{'])
+    };
+    let components: Vec<_> = Path::new(pattern).components().collect();
+    let literal_count = components.iter().take_while(|component| is_literal(component)).count();
+    let base: PathBuf = components[..literal_count].iter().collect();
+    let relative_pattern: PathBuf = components[literal_count..].iter().collect();
+    let relative_pattern = relative_pattern.to_string_lossy().to_string();
+    let base = if base.as_os_str().is_empty() { PathBuf::from(".") } else { base };
+    let base = base.absolutize()?.to_path_buf();
+    let out_dir = out_dir.absolutize()?.to_path_buf();
+    info!("optimizing images matching {} below {:?} into {:?}", relative_pattern, base, out_dir);
+
+    let walker = globwalk::GlobWalkerBuilder::from_patterns(&base, &[relative_pattern.as_str()])
+        .file_type(GlobFileType::FILE)
+        .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+        .build()?
+        .filter_map(Result::ok);
+    let mut files = vec![];
+    for entry in walker {
+        let path = entry.path();
+        let is_candidate = is_image(path) || (opts.sniff && sniffed_extension(path)?.is_some());
+        if !is_candidate {
+            debug!("{:?} is not an image, skipping", path);
+            continue;
+        }
+        files.push(path.to_path_buf());
+    }
+    let stats = process_listed_files(&files, &base, &out_dir, opts, None, &HashMap::new())?;
+    info!("{} images optimized, {} failed", stats.images, stats.failed);
+    if let Some(report) = &opts.report {
+        write_report(Path::new(report), pattern, &stats)?;
+    }
+    Ok(())
+}
+
+/// Polls `watched` every --watch-interval and processes each archive found there into `out_dir`
+/// once its size and modification time stayed the same over a whole interval. Runs until the
+/// process is stopped; a failed archive is logged and retried when it changes.
+fn watch_dir(watched: &Path, out_dir: &Path, opts: &Opts) -> Result<()> {
+    if !watched.is_dir() {
+        bail!("--watch {:?} isn't a directory", watched);
+    }
+    std::fs::create_dir_all(out_dir)?;
+    if let Some(done_dir) = &opts.watch_done {
+        std::fs::create_dir_all(done_dir)?;
+    }
+    let interval = Duration::from_secs(opts.watch_interval.max(1));
+    info!("watching {:?} every {}s, writing to {:?}", watched, interval.as_secs(), out_dir);
+    // size and modification time of the archives seen at the previous scan, and of those already handled
+    let mut seen: HashMap<PathBuf, (u64, SystemTime)> = HashMap::new();
+    let mut handled: HashMap<PathBuf, (u64, SystemTime)> = HashMap::new();
+    loop {
+        for source in watched_archives(watched)? {
+            // gone since the listing: moved away or still being renamed into place
+            let Ok(metadata) = std::fs::metadata(&source) else { continue };
+            let state = (metadata.len(), metadata.modified()?);
+            if handled.get(&source) == Some(&state) {
+                continue;
+            }
+            if seen.insert(source.clone(), state) != Some(state) {
+                debug!("{:?} is new or still being written, waiting", source);
+                continue;
+            }
+            seen.remove(&source);
+            handled.insert(source.clone(), state);
+            let target = watch_target(out_dir, &source);
+            // a source modified after its target was written changed since it was processed
+            let changed = std::fs::metadata(&target).and_then(|target| target.modified()).is_ok_and(|written| written < state.1);
+            let archive = Opts {
+                source: source.to_string_lossy().to_string(),
+                target: target.to_string_lossy().to_string(),
+                watch: None,
+                out_dir: None,
+                // nobody is there to answer a prompt
+                overwrite: Some(opts.overwrite.clone().unwrap_or_else(|| if changed { "overwrite" } else { "skip" }.to_string())),
+                ..opts.clone()
+            };
+            info!("{:?} landed, processing it into {}", source, archive.target);
+            match process_archive(&archive) {
+                Ok(()) => {
+                    if let Some(done_dir) = &opts.watch_done {
+                        let done = Path::new(done_dir).join(source.file_name().unwrap_or_default());
+                        move_file(&source, &done)?;
+                        handled.remove(&source);
+                        info!("{:?} moved to {:?}", source, done);
+                    }
+                }
+                Err(error) => error!("{} failed: {:#}; it will be retried once it changes", archive.source, error),
+            }
+        }
+        seen.retain(|path, _| path.exists());
+        handled.retain(|path, _| path.exists());
+        thread::sleep(interval);
+    }
+}
+
+/// The archives directly in the --watch directory, hidden files left out (partial downloads, `.part`...).
+fn watched_archives(watched: &Path) -> Result<Vec<PathBuf>> {
+    let mut archives = vec![];
+    for entry in std::fs::read_dir(watched)? {
+        let path = entry?.path();
+        let hidden = path.file_name().is_some_and(|name| name.to_string_lossy().starts_with('.'));
+        let is_archive = ["zip", "cbz", "pdf"].iter().any(|extension| has_extension(&path, extension))
+            || tarball_compression(&path).is_some();
+        if path.is_file() && is_archive && !hidden {
+            archives.push(path);
+        }
+    }
+    archives.sort();
+    Ok(archives)
+}
+
+/// Output of a watched archive: the same name in `out_dir` for a zip or cbz, `<stem>.cbz` for the other sources.
+fn watch_target(out_dir: &Path, source: &Path) -> PathBuf {
+    let name = source.file_name().unwrap_or_default().to_string_lossy().to_string();
+    if has_extension(source, "zip") || has_extension(source, "cbz") {
+        return out_dir.join(name);
+    }
+    let lowercase = name.to_lowercase();
+    let stem_length = [".tar.gz", ".tar.bz2", ".tgz", ".tbz2", ".pdf"].iter()
+        .find(|suffix| lowercase.ends_with(*suffix))
+        .map_or(name.len(), |suffix| name.len() - suffix.len());
+    out_dir.join(format!("{}.cbz", &name[..stem_length]))
+}
+
+/// Renames `from` to `to`, copying then removing it when they are on different file systems.
+fn move_file(from: &Path, to: &Path) -> Result<()> {
+    if std::fs::rename(from, to).is_err() {
+        std::fs::copy(from, to)?;
+        std::fs::remove_file(from)?;
+    }
+    Ok(())
+}
+
+fn is_image(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| IMAGE_EXTENSIONS.contains(&ext.to_string_lossy().to_lowercase().as_str()))
+}
+
+fn process_archive(opts: &Opts)-> Result<()> {
+    if let Some(min_free_space) = opts.min_free_space {
+        wait_for_free_space(opts, min_free_space)?;
+    }
+    info!("creating temp dirs");
+    let unpack_dir = tempfile::Builder::new().prefix("img-optim-unpack").tempdir()?;
+    let processed_dir = tempfile::Builder::new().prefix("img-optim-processed").tempdir()?;
+    info!("temp dirs created [unpack_dir={:?} processed_dir={:?}]", unpack_dir, processed_dir);
+
+    let result = {
+        let _span = TRACER.span("archive", "archive", &opts.source);
+        process_archive_in(opts, &unpack_dir, &processed_dir)
+    };
+    if opts.keep_temp {
+        // with --no-repack the processed dir already became the output
+        for kept in [unpack_dir.into_path(), processed_dir.into_path()] {
+            if kept.exists() {
+                info!("temp dir kept: {:?}", kept);
+            }
+        }
+    }
+    result
+}
+
+/// How long --min-free-space waits for other jobs to release disk space before giving up.
+const FREE_SPACE_WAIT: Duration = Duration::from_secs(30 * 60);
+
+/// Waits until the temp volume can take the unpacked archive and its processed copy,
+/// estimated from the entry sizes of the central directory, with `min_free_space` to spare.
+fn wait_for_free_space(opts: &Opts, min_free_space: u64) -> Result<()> {
+    let sources = if opts.merge { merge_sources(opts)? } else { vec![opts.source.clone()] };
+    let mut uncompressed = 0;
+    for source in &sources {
+        if source == "-" || !has_extension(Path::new(source), "zip") && !has_extension(Path::new(source), "cbz") {
+            debug!("can't estimate the unpacked size of {}, only keeping --min-free-space free", source);
+            continue;
+        }
+        let mut archive = zip::ZipArchive::new(std::fs::File::open(source)?)?;
+        for index in 0..archive.len() {
+            uncompressed += archive.by_index_raw(index)?.size();
+        }
+    }
+    let needed = uncompressed * 2 + min_free_space;
+    let temp_dir = std::env::temp_dir();
+    let started = Instant::now();
+    let mut logged: Option<Instant> = None;
+    loop {
+        let Some(free) = free_space(&temp_dir) else {
+            warn!("can't read the free space of {:?}, not waiting for it", temp_dir);
+            return Ok(());
+        };
+        if free >= needed {
+            return Ok(());
+        }
+        if started.elapsed() > FREE_SPACE_WAIT {
+            bail!("{:?} still has only {} bytes free after {}, {} needs {}", temp_dir, free,
+                humantime::format_duration(FREE_SPACE_WAIT), opts.source, needed);
+        }
+        if logged.is_none_or(|logged| logged.elapsed() >= Duration::from_secs(60)) {
+            info!("waiting for disk space: {:?} has {} bytes free, {} needs {}", temp_dir, free, opts.source, needed);
+            logged = Some(Instant::now());
+        }
+        thread::sleep(Duration::from_secs(5));
+    }
+}
+
+#[cfg(unix)]
+fn free_space(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn free_space(_path: &Path) -> Option<u64> {
+    None
+}
+
+fn process_archive_in(opts: &Opts, unpack_dir: &tempfile::TempDir, processed_dir: &tempfile::TempDir) -> Result<()> {
+    let to_stdout = opts.target == "-";
+    if to_stdout && opts.no_repack == Some(true) {
+        bail!("--no-repack needs a target directory name, not `-`");
+    }
+
+    let target_zip = Path::new(&opts.target).absolutize()?;
+    info!("target zip path: {:?}",target_zip);
+
+    if to_stdout && opts.split_size.is_some() {
+        bail!("--split-size writes several archives, it needs a target name, not `-`");
+    }
+    let output_path = match opts.no_repack {
+        Some(true) => PathBuf::from(target_zip.file_stem().unwrap_or_default()),
+        _ if opts.split_size.is_some() => part_path(&target_zip, 1),
+        _ => target_zip.to_path_buf(),
+    };
+    let updating = (opts.update || opts.content_cache) && output_path.exists();
+    if !to_stdout && !updating && !should_write_target(&output_path, opts)? {
+        info!("skipping {}, target {:?} already exists", opts.source, output_path);
+        return Ok(());
+    }
+
+    info!("start unpacking");
+    let unpack_span = TRACER.span("phase", "unpack", &opts.source);
+    // zip needs to seek, so an archive read from stdin is buffered to a temp file first
+    let stdin_copy = if opts.source == "-" { Some(buffer_stdin()?) } else { None };
+    let source_path = match &stdin_copy {
+        Some(copy) => Cow::Borrowed(copy.path()),
+        None => Path::new(&opts.source).absolutize()?,
+    };
+    let metadata = if opts.merge {
+        unpack_merged(&merge_sources(opts)?, unpack_dir.path(), opts)?
+    } else {
+        unpack_source(&source_path, unpack_dir.path(), opts)?
+    };
+    info!("unpacking done");
+    drop(unpack_span);
+    let markers = find_markers(unpack_dir.path(), opts.merge)?;
+    if !markers.is_empty() {
+        if !opts.reprocess {
+            warn!("skipping {}, already optimized by img-optim ({}), use --reprocess to convert it again", opts.source, markers.join(", "));
+            return Ok(());
+        }
+        info!("{} was already optimized by img-optim ({}), reprocessing it", opts.source, markers.join(", "));
+    }
+
+    if opts.warn_mixed_formats {
+        let formats = page_formats(unpack_dir.path(), opts)?;
+        if formats.len() > 1 {
+            let counts: Vec<String> = formats.iter().map(|(format, count)| format!("{} {}", count, format)).collect();
+            warn!("{} mixes page formats: {}", opts.source, counts.join(", "));
+        }
+    }
+
+    let baseline_bytes = match &opts.baseline {
+        Some(_) if opts.source == "-" => {
+            info!("not comparing an archive read from stdin with --baseline");
+            None
+        }
+        Some(baseline) => Some(check_baseline(Path::new(baseline), &source_path.to_string_lossy(), unpack_dir.path(), opts)?),
+        None => None,
+    };
+
+    info!("start processing files");
+    let process_span = TRACER.span("phase", "process", &opts.source);
+    let page_options = load_page_options(&source_path, unpack_dir.path(), opts)?;
+    let update_base = if updating && !is_tar_zst(&output_path) {
+        info!("updating {:?}, keeping the entries that are up to date", output_path);
+        let cache = if opts.content_cache { ContentCache::load(&output_path, opts, &page_options)? } else { None };
+        Some(UpdateBase::open(&output_path, &metadata, cache)?)
+    } else {
+        if updating {
+            warn!("can't read the previous {:?} to update it, converting everything", output_path);
+        }
+        None
+    };
+    let mut stats = process_files(&unpack_dir.path(), processed_dir.path(), opts, update_base.as_ref(), &page_options)?;
+    info!("processing done");
+    drop(process_span);
+    info!("{} images found in {}", stats.found, opts.source);
+    if stats.found == 0 && !opts.copy_only {
+        if opts.fail_on_empty {
+            bail!("no image found in {} (--fail-on-empty)", opts.source);
+        }
+        warn!("no image found in {}, the output only holds its other files", opts.source);
+    }
+    if !stats.failures.is_empty() {
+        warn!("{} input files produced no output: {:?}", stats.failures.len(), stats.failures);
+    }
+    let processed_files = list_entries(processed_dir.path())?.iter()
+        .filter(|sub_path| processed_dir.path().join(sub_path).is_file())
+        .count();
+    check_page_count(&stats, processed_files, "processed directory")?;
+    if let Some(baseline_bytes) = baseline_bytes {
+        let output_bytes: u64 = source_sizes(processed_dir.path(), opts)?.values().sum();
+        info!("{} images now take {} bytes, {:.1}% of the {} bytes of the baseline", opts.source, output_bytes,
+            100.0 * output_bytes as f64 / baseline_bytes.max(1) as f64, baseline_bytes);
+    }
+
+    if opts.sanitize_names {
+        let renames = sanitize_names(processed_dir.path(), &opts.safe_chars)?;
+        stats.rename_outputs(processed_dir.path(), &renames);
+    }
+    if let Some(report) = &opts.report {
+        write_report(Path::new(report), &opts.source, &stats)?;
+    }
+    if let Some(compare_dir) = &opts.compare {
+        write_comparisons(Path::new(compare_dir), &source_path, &stats, opts)?;
+    }
+    if let Some(template) = &opts.contact_sheet {
+        let named = if to_stdout { &source_path } else { &target_zip };
+        let sheet = PathBuf::from(render_target_template(template, named, ""));
+        write_contact_sheet(processed_dir.path(), &sheet, opts)?;
+    }
+    if opts.marker {
+        write_marker(processed_dir.path(), opts, &page_options)?;
+        stats.own_files += 1;
+    }
+    if opts.embed_report && (opts.no_repack != Some(true) || opts.embed_report_in_dir) {
+        write_embedded_report(&processed_dir.path().join(&opts.embed_report_name), &opts.source, &stats)?;
+        stats.own_files += 1;
+    } else if opts.embed_report {
+        info!("not embedding the report in the output directory, use --embed-report-in-dir for that");
+    }
+    let source_mtime = match opts.preserve_mtime && !to_stdout {
+        true => source_mtime(opts)?,
+        false => None,
+    };
+
+    let _repack_span = TRACER.span("phase", "repack", &opts.source);
+    let result = match opts.no_repack {
+        None | Some(false) if to_stdout => {
+            info!("start zipping output to stdout");
+            let packed = tempfile::Builder::new().prefix("img-optim-output").suffix(".zip").tempfile()?;
+            repack_output(processed_dir, packed.path(), opts, &metadata)?;
+            let mut stdout = std::io::stdout().lock();
+            std::io::copy(&mut std::fs::File::open(packed.path())?, &mut stdout)?;
+            stdout.flush()?;
+            info!("zipping done");
+            Ok(())
+        }
+        None | Some(false) if opts.split_size.is_some() => {
+            info!("start zipping output in parts");
+            let parts = write_parts(processed_dir.path(), &target_zip, opts.split_size.unwrap_or_default(), opts, &metadata, &stats)?;
+            info!("zipping done");
+            for (part, part_dir) in &parts {
+                println!("{}", part.display());
+                if let Some(mtime) = source_mtime {
+                    set_mtime(part, mtime)?;
+                }
+                if let Some(manifest) = &opts.manifest {
+                    write_manifest(Path::new(manifest), part, part_dir.path(), opts.manifest_entries)?;
+                }
+            }
+            Ok(())
+        }
+        None | Some(false) => {
+            info!("start zipping output");
+            let result = write_atomically(&target_zip, |partial| {
+                repack_output(processed_dir, partial, opts, &metadata)?;
+                if !is_tar_zst(&target_zip) {
+                    check_page_count(&stats, count_zip_files(partial)?, "archive")?;
+                }
+                Ok(())
+            });
+            info!("zipping done");
+            if let (Ok(()), Some(mtime)) = (&result, source_mtime) {
+                set_mtime(&target_zip, mtime)?;
+            }
+            if let (Ok(()), true) = (&result, opts.content_cache) {
+                ContentCache { options: image_options_hash(opts, &page_options)?, entries: stats.cached.clone() }.save(&target_zip)?;
+            }
+            if let (Ok(()), Some(manifest)) = (&result, &opts.manifest) {
+                write_manifest(Path::new(manifest), &target_zip, processed_dir.path(), opts.manifest_entries)?;
+            }
+            result
+        }
+        Some(true) => {
+            let temp_name = processed_dir.path().file_name()
+                    .map(|p| p.to_owned());
+            let dest_path = target_zip.into_owned();
+            let dest_path = dest_path.file_stem();
+            let persisted = processed_dir.path().to_path_buf();
+            remove_existing(&output_path)?;
+            let copy_opt = fs_extra::dir::CopyOptions::new();
+            fs_extra::move_items(&[persisted], ".", &copy_opt)?;
+            match (temp_name, dest_path) {
+                (Some(old), Some(new)) => {
+                    std::fs::rename(old, new)?;
+                    if opts.hardlink_duplicates {
+                        hardlink_duplicates(Path::new(new))?;
+                    }
+                    if let Some(mtime) = source_mtime {
+                        set_mtime(Path::new(new), mtime)?;
+                    }
+                    Ok(())
+                }
+                _ => { Ok(()) }
+            }
+        }
+    };
+    result
+}
+
+/// Modification time of the source, the newest one when merging; None when read from stdin.
+fn source_mtime(opts: &Opts) -> Result<Option<SystemTime>> {
+    let sources = if opts.merge { merge_sources(opts)? } else { vec![opts.source.clone()] };
+    let mut newest = None;
+    for source in sources.iter().filter(|source| *source != "-") {
+        let modified = std::fs::metadata(source)?.modified()?;
+        newest = newest.max(Some(modified));
+    }
+    Ok(newest)
+}
+
+/// Sets the modification time of `path`, or of everything below it when it is a directory.
+fn set_mtime(path: &Path, mtime: SystemTime) -> Result<()> {
+    let mut paths = vec![];
+    if path.is_dir() {
+        // contents first, setting a file's time doesn't touch its directory's but creating it did
+        paths.extend(list_entries(path)?.iter().rev().map(|sub_path| path.join(sub_path)));
+    }
+    paths.push(path.to_path_buf());
+    for path in paths {
+        std::fs::File::open(path)?.set_modified(mtime)?;
+    }
+    Ok(())
+}
+
+/// Writes `sample` montages, spread evenly over the converted images, each showing
+/// the original next to the optimized image with their sizes.
+fn write_comparisons(compare_dir: &Path, source: &Path, stats: &ArchiveStats, opts: &Opts) -> Result<()> {
+    let sample = opts.sample;
+    if stats.conversions.is_empty() || sample == 0 {
+        return Ok(());
+    }
+    create_dir_all(compare_dir)?;
+    let archive_stem = source.file_stem().unwrap_or_default().to_string_lossy().to_string();
+    let count = sample.min(stats.conversions.len());
+    for index in 0..count {
+        let (original, produced) = &stats.conversions[index * stats.conversions.len() / count];
+        let optimized = match produced.first() {
+            Some(optimized) => optimized,
+            None => continue,
+        };
+        let montage = compare_dir.join(format!("{}-{:03}.jpg", archive_stem, index + 1));
+        let label = |name: &str, path: &Path| -> Result<String> {
+            Ok(format!("{}\\n{} KB", name, std::fs::metadata(path)?.len() / 1024))
+        };
+        let mut command = gm_command(opts);
+        command.arg("montage")
+            .arg("-label").arg(label("original", original)?).arg(original)
+            .arg("-label").arg(label("optimized", optimized)?).arg(optimized)
+            .args(["-tile", "2x1", "-geometry", "800x1200+8+8"])
+            .arg(&montage);
+        let output = run_gm(&mut command, opts.image_timeout)?;
+        if !output.status.success() {
+            bail!("`gm montage` invocation failed\n{}\n", String::from_utf8_lossy(&output.stderr));
+        }
+        info!("comparison for {:?} written to {:?}", original, montage);
+    }
+    Ok(())
+}
+
+fn buffer_stdin() -> Result<tempfile::NamedTempFile> {
+    let mut copy = tempfile::Builder::new().prefix("img-optim-stdin").suffix(".zip").tempfile()?;
+    let size = std::io::copy(&mut std::io::stdin().lock(), copy.as_file_mut())?;
+    debug!("buffered {} bytes from stdin to {:?}", size, copy.path());
+    Ok(copy)
+}
+
+fn should_write_target(output_path: &Path, opts: &Opts) -> Result<bool> {
+    if !output_path.exists() {
+        return Ok(true);
+    }
+    let interactive = std::io::stdin().is_terminal();
+    let policy = opts.overwrite.as_deref().unwrap_or(if interactive { "prompt" } else { "skip" });
+    match policy {
+        "overwrite" => Ok(true),
+        "prompt" => Ok(Confirm::new()
+            .with_prompt(format!("{:?} already exists, overwrite it?", output_path))
+            .interact()?),
+        _ => Ok(false),
+    }
+}
+
+/// Removes a previous output so it is replaced rather than updated in place.
+fn remove_existing(output_path: &Path) -> Result<()> {
+    if output_path.is_dir() {
+        debug!("removing previous output directory {:?}", output_path);
+        std::fs::remove_dir_all(output_path)?;
+    } else if output_path.exists() {
+        debug!("removing previous output {:?}", output_path);
+        std::fs::remove_file(output_path)?;
+    }
+    Ok(())
+}
+
+fn has_extension(path: &Path, extension: &str) -> bool {
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case(extension))
+}
+
+fn unpack_source(source_path: &Path, tmp_dir: &Path, opts: &Opts) -> Result<ArchiveMetadata> {
+    if has_extension(source_path, "pdf") {
+        rasterize_pdf(source_path, tmp_dir, opts)?;
+        Ok(ArchiveMetadata::default())
+    } else if let Some(compression) = tarball_compression(source_path) {
+        unpack_tarball(source_path, compression, tmp_dir, opts)
+    } else {
+        unpack_archive(source_path, tmp_dir, opts)
+    }
+}
+
+/// The archives given to --merge, --source ones first then the --from-list ones.
+fn merge_sources(opts: &Opts) -> Result<Vec<String>> {
+    let mut sources = opts.merge_sources.clone();
+    if let Some(list) = &opts.from_list {
+        let content = std::fs::read_to_string(list)
+            .map_err(|error| anyhow!("couldn't read --from-list {}: {}", list, error))?;
+        sources.extend(content.lines().map(str::trim).filter(|line| !line.is_empty()).map(str::to_string));
+    }
+    if sources.is_empty() {
+        bail!("--merge needs at least one --source or a --from-list");
+    }
+    Ok(sources)
+}
+
+/// Unpacks each source below `01-<stem>/`, `02-<stem>/`... so that pages keep the order of the
+/// sources and same-named entries of different sources can't collide.
+fn unpack_merged(sources: &[String], tmp_dir: &Path, opts: &Opts) -> Result<ArchiveMetadata> {
+    let width = sources.len().to_string().len().max(2);
+    let mut metadata = ArchiveMetadata::default();
+    for (index, source) in sources.iter().enumerate() {
+        let source_path = Path::new(source).absolutize()?;
+        let stem = source_path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+        let prefix = PathBuf::from(format!("{:0width$}-{}", index + 1, stem, width = width));
+        info!("merging {} as {:?}", source, prefix);
+        let source_dir = tmp_dir.join(&prefix);
+        create_dir_all(&source_dir)?;
+        let unpacked = unpack_source(&source_path, &source_dir, opts)?;
+        if !unpacked.comment.is_empty() {
+            warn!("comment of {} will not be kept in the merged archive", source);
+        }
+        metadata.modified.extend(unpacked.modified.into_iter().map(|(path, modified)| (prefix.join(path), modified)));
+    }
+    Ok(metadata)
+}
+
+/// Renders every page of a PDF as a PNG in `tmp_dir`, numbered so that name order is page order.
+fn rasterize_pdf(pdf_path: &Path, tmp_dir: &Path, opts: &Opts) -> Result<()> {
+    // gm delegates PDF reading to ghostscript, check for it to fail with a clear message
+    if Command::new("gs").arg("--version").output().is_err() {
+        bail!("rasterizing {:?} needs ghostscript (`gs`), which GraphicsMagick uses as its PDF delegate", pdf_path);
+    }
+    let pages = tmp_dir.join("page-%04d.png");
+    let mut command = gm_command(opts);
+    command.arg("convert")
+        .arg("-density").arg(opts.pdf_dpi.to_string())
+        .arg(pdf_path)
+        .arg("+adjoin")
+        .arg(&pages);
+    let output = run_gm(&mut command, None)?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        let error = format!("`gm convert` failed to rasterize {:?}\n{}\n",
+                            pdf_path, String::from_utf8_lossy(&output.stderr));
+        Err(anyhow!(error))
+    }
+}
+
+fn unpack_archive(zip_path: &Path, tmp_dir: &Path, opts: &Opts) -> Result<ArchiveMetadata> {
+    let zip_file = std::fs::File::open(zip_path)?;
+    let mut archive = zip::ZipArchive::new(zip_file)?;
+    let mut metadata = ArchiveMetadata {
+        comment: if opts.drop_comments { vec![] } else { archive.comment().to_vec() },
+        modified: HashMap::new(),
+    };
+    if let Some(max_entries) = opts.max_entries {
+        if archive.len() > max_entries {
+            bail!("archive {:?} declares {} entries, more than the allowed {} (--max-entries)",
+                zip_path, archive.len(), max_entries);
+        }
+    }
+    // names, directories and checks in entry order first, then the file contents, possibly in parallel
+    let mut seen_names = HashSet::new();
+    let mut files = vec![];
+    for i in 0..archive.len() {
+        let file = archive.by_index_raw(i)?;
+        let is_dir = file.name().ends_with('/');
+        if !opts.drop_comments && !file.comment().is_empty() {
+            // the zip writer has no way to set per-entry comments
+            warn!("comment of entry {:?} will not be kept: {:?}", file.name(), file.comment());
+        }
+        let out_path = match file.enclosed_name() {
+            Some(path) => path.to_owned(),
+            None => bail!("archive entry {:?} has an unsafe path that would escape {:?}, refusing", file.name(), tmp_dir),
+        };
+        let (entry_name, out_path) = if opts.normalize_names {
+            let normalized = normalize_entry_name(file.name(), is_dir, opts.lowercase_names, &mut seen_names);
+            let normalized_path = PathBuf::from(&normalized);
+            (normalized, normalized_path)
+        } else {
+            (file.name().to_string(), out_path)
+        };
+        let full_out_path = enclosed_path(tmp_dir, &entry_name, &out_path)?;
+        debug!("unpack {:?} to {:?}", out_path, full_out_path);
+        if let Some(modified) = file.last_modified() {
+            metadata.modified.insert(out_path.clone(), modified);
+        }
+
+        if is_dir {
+            debug!("create dir {:?}", full_out_path);
+            std::fs::create_dir_all(&full_out_path)?;
+            set_unix_mode(&full_out_path, file.unix_mode())?;
+        } else {
+            create_parent(&full_out_path)?;
+            files.push((i, full_out_path));
+        }
+    }
+
+    let jobs = job_count(opts)?.min(files.len()).max(1);
+    let uncompressed_total = AtomicU64::new(0);
+    if jobs == 1 {
+        extract_entries(&mut archive, &files, zip_path, opts, &uncompressed_total)?;
+    } else {
+        // a ZipArchive reads through one file handle, each worker opens its own over a range of entries
+        debug!("unpacking {} files with {} workers", files.len(), jobs);
+        let uncompressed_total = &uncompressed_total;
+        thread::scope(|scope| {
+            let workers: Vec<_> = files.chunks(files.len().div_ceil(jobs)).map(|range| scope.spawn(move || {
+                let mut archive = zip::ZipArchive::new(std::fs::File::open(zip_path)?)?;
+                extract_entries(&mut archive, range, zip_path, opts, uncompressed_total)
+            })).collect();
+            workers.into_iter().try_for_each(|worker| worker.join().expect("unpack worker panicked"))
+        })?;
+    }
+    Ok(metadata)
+}
+
+/// Worker count from --jobs, `auto` being one per CPU (process_files lowers it by image size).
+fn job_count(opts: &Opts) -> Result<usize> {
+    match opts.jobs.as_str() {
+        "auto" => Ok(thread::available_parallelism().map_or(1, |count| count.get())),
+        count => count.parse::<usize>().ok().filter(|count| *count > 0)
+            .ok_or_else(|| anyhow!("invalid --jobs '{}', expected a positive number or `auto`", count)),
+    }
+}
+
+/// Writes the content of the zip entries at `files` (index and destination), with the CRC and
+/// --max-uncompressed checks; `uncompressed_total` is shared by all the workers of an archive.
+fn extract_entries(archive: &mut zip::ZipArchive<std::fs::File>, files: &[(usize, PathBuf)], zip_path: &Path, opts: &Opts,
+                   uncompressed_total: &AtomicU64) -> Result<()> {
+    for (index, full_out_path) in files {
+        let mut file = archive.by_index(*index)?;
+        let mut out_file = CrcWriter::new(std::fs::File::create(full_out_path)?);
+        // read at most one byte past the limit, enough to know it was exceeded
+        let allowed = opts.max_uncompressed
+            .map_or(u64::MAX, |limit| limit.saturating_sub(uncompressed_total.load(Ordering::Relaxed)).saturating_add(1));
+        // stopping at the declared size never reaches the end of the data, where the zip reader
+        // checks the CRC itself; it is compared below instead, --no-verify-crc keeping a mismatch
+        let declared = file.size();
+        let copied = std::io::copy(&mut (&mut file).take(declared.min(allowed)), &mut out_file)
+            .map_err(|error| anyhow!("couldn't extract {:?} from {:?}: {}", file.name(), zip_path, error))?;
+        let total = uncompressed_total.fetch_add(copied, Ordering::Relaxed) + copied;
+        if let Some(limit) = opts.max_uncompressed {
+            if total > limit {
+                bail!("uncompressed size of archive {:?} exceeds {} bytes while extracting {:?} (--max-uncompressed)",
+                    zip_path, limit, file.name());
+            }
+        }
+        if copied < declared {
+            bail!("{:?} in {:?} is truncated: {} bytes of {}", file.name(), zip_path, copied, declared);
+        }
+        let crc = out_file.crc();
+        if crc != file.crc32() {
+            if opts.no_verify_crc {
+                warn!("{:?} in {:?} is corrupted (CRC {:08x}, expected {:08x}), keeping it anyway", file.name(), zip_path, crc, file.crc32());
+            } else {
+                bail!("{:?} in {:?} is corrupted: CRC {:08x}, expected {:08x}", file.name(), zip_path, crc, file.crc32());
+            }
+        }
+        set_unix_mode(full_out_path, file.unix_mode())?;
+    }
+    Ok(())
+}
+
+fn set_unix_mode(path: &Path, mode: Option<u32>) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        if let Some(mode) = mode {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = (path, mode);
+    Ok(())
+}
+
+#[derive(Clone, Copy)]
+enum TarballCompression {
+    Gzip,
+    Bzip2,
+}
+
+/// The compression of a `.tar.gz`/`.tgz` or `.tar.bz2`/`.tbz2` source, by its name.
+fn tarball_compression(path: &Path) -> Option<TarballCompression> {
+    let name = path.to_str()?.to_lowercase();
+    if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        Some(TarballCompression::Gzip)
+    } else if name.ends_with(".tar.bz2") || name.ends_with(".tbz2") {
+        Some(TarballCompression::Bzip2)
+    } else {
+        None
+    }
+}
+
+/// Unpacks a compressed tarball with the same checks as a zip: entry paths kept inside `tmp_dir`,
+/// --max-entries and --max-uncompressed. Only files and directories are supported, anything
+/// else (links, sparse files, device nodes, FIFOs) fails the archive.
+#[cfg(feature = "tarballs")]
+fn unpack_tarball(tar_path: &Path, compression: TarballCompression, tmp_dir: &Path, opts: &Opts) -> Result<ArchiveMetadata> {
+    let file = std::io::BufReader::new(std::fs::File::open(tar_path)?);
+    let decoder: Box<dyn Read> = match compression {
+        TarballCompression::Gzip => Box::new(flate2::read::MultiGzDecoder::new(file)),
+        TarballCompression::Bzip2 => Box::new(bzip2::read::MultiBzDecoder::new(file)),
+    };
+    let truncated = |error: std::io::Error| anyhow!("couldn't read {:?}, truncated or corrupted? {}", tar_path, error);
+    let mut archive = tar::Archive::new(decoder);
+    let mut uncompressed_total: u64 = 0;
+    let mut seen_names = HashSet::new();
+    for (index, entry) in archive.entries().map_err(truncated)?.enumerate() {
+        let mut entry = entry.map_err(truncated)?;
+        if opts.max_entries.is_some_and(|max_entries| index >= max_entries) {
+            bail!("archive {:?} has more than the allowed {} entries (--max-entries)", tar_path, index);
+        }
+        let name = entry.path().map_err(truncated)?.to_string_lossy().to_string();
+        let entry_type = entry.header().entry_type();
+        let is_dir = entry_type.is_dir();
+        if !is_dir && !entry_type.is_file() {
+            let kind = match entry_type {
+                tar::EntryType::GNUSparse => "a sparse file",
+                tar::EntryType::Char | tar::EntryType::Block => "a device node",
+                tar::EntryType::Fifo => "a FIFO",
+                tar::EntryType::Symlink | tar::EntryType::Link => "a link",
+                _ => "an unsupported entry type",
+            };
+            bail!("entry {:?} of {:?} is {}, only files and directories are supported", name, tar_path, kind);
+        }
+        let (entry_name, out_path) = if opts.normalize_names {
+            let normalized = normalize_entry_name(&name, is_dir, opts.lowercase_names, &mut seen_names);
+            let normalized_path = PathBuf::from(&normalized);
+            (normalized, normalized_path)
+        } else {
+            (name.clone(), PathBuf::from(&name))
+        };
+        let full_out_path = enclosed_path(tmp_dir, &entry_name, &out_path)?;
+        debug!("unpack {:?} to {:?}", out_path, full_out_path);
+        if is_dir {
+            debug!("create dir {:?}", full_out_path);
+            std::fs::create_dir_all(&full_out_path)?;
+            continue;
+        }
+        create_parent(&full_out_path)?;
+        let mut out_file = std::fs::File::create(&full_out_path)?;
+        let allowed = opts.max_uncompressed
+            .map_or(u64::MAX, |limit| limit.saturating_sub(uncompressed_total).saturating_add(1));
+        let copied = std::io::copy(&mut (&mut entry).take(allowed), &mut out_file).map_err(truncated)?;
+        uncompressed_total += copied;
+        if let Some(limit) = opts.max_uncompressed {
+            if uncompressed_total > limit {
+                bail!("uncompressed size of archive {:?} exceeds {} bytes while extracting {:?} (--max-uncompressed)",
+                    tar_path, limit, name);
+            }
+        }
+        if copied != entry.size() {
+            bail!("{:?} in {:?} has {} bytes instead of {}, the archive is truncated", name, tar_path, copied, entry.size());
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            if let Ok(mode) = entry.header().mode() {
+                std::fs::set_permissions(&full_out_path, std::fs::Permissions::from_mode(mode))?;
+            }
+        }
+    }
+    // tar has no archive comment, and its modification times aren't carried to the zip entries
+    Ok(ArchiveMetadata::default())
+}
+
+#[cfg(not(feature = "tarballs"))]
+fn unpack_tarball(tar_path: &Path, _compression: TarballCompression, _tmp_dir: &Path, _opts: &Opts) -> Result<ArchiveMetadata> {
+    bail!("reading {:?} requires img-optim to be built with the `tarballs` feature", tar_path)
+}
+
+/// Renames the files below `dir` to names made only of `safe_chars`, directories included.
+/// Names already safe are kept; a sanitized name taken by another file gets a `-2`, `-3`... suffix.
+/// Returns the new name of each renamed file, by its previous path below `dir`.
+fn sanitize_names(dir: &Path, safe_chars: &str) -> Result<HashMap<PathBuf, PathBuf>> {
+    let unsafe_run = Regex::new(&format!("[^{}]+", safe_chars))
+        .map_err(|error| anyhow!("invalid --safe-chars '{}': {}", safe_chars, error))?;
+    let sanitize = |sub_path: &Path| -> PathBuf {
+        sub_path.components()
+            .map(|part| unsafe_run.replace_all(&part.as_os_str().to_string_lossy(), "_").to_string())
+            .collect()
+    };
+    let files: Vec<PathBuf> = list_entries(dir)?.into_iter()
+        .filter(|sub_path| dir.join(sub_path).is_file())
+        .collect();
+    let mut renames = HashMap::new();
+    // safe names first, so that they keep their name whatever sorts before them
+    let mut taken: HashSet<String> = files.iter()
+        .filter(|sub_path| sanitize(sub_path) == **sub_path)
+        .map(|sub_path| entry_name(sub_path).to_lowercase())
+        .collect();
+    for sub_path in &files {
+        let sanitized = sanitize(sub_path);
+        if sanitized == *sub_path {
+            continue;
+        }
+        let stem = sanitized.file_stem().map_or_else(String::new, |stem| stem.to_string_lossy().to_string());
+        let extension = sanitized.extension().map_or_else(String::new, |ext| format!(".{}", ext.to_string_lossy()));
+        let mut renamed = sanitized.clone();
+        let mut counter = 1;
+        while !taken.insert(entry_name(&renamed).to_lowercase()) {
+            counter += 1;
+            renamed = sanitized.with_file_name(format!("{}-{}{}", stem, counter, extension));
+        }
+        debug!("sanitize {:?} => {:?}", sub_path, renamed);
+        let destination = dir.join(&renamed);
+        create_parent(&destination)?;
+        std::fs::rename(dir.join(sub_path), destination)?;
+        renames.insert(sub_path.clone(), renamed);
+    }
+    // the directories whose files all moved away
+    for sub_path in list_entries(dir)?.iter().rev() {
+        let full_path = dir.join(sub_path);
+        if full_path.is_dir() && sanitize(sub_path) != *sub_path {
+            let _ = std::fs::remove_dir(full_path);
+        }
+    }
+    Ok(renames)
+}
+
+/// Writer computing the CRC32 of what goes through it.
+struct CrcWriter<W> {
+    inner: W,
+    hasher: crc32fast::Hasher,
+}
+
+impl<W> CrcWriter<W> {
+    fn new(inner: W) -> CrcWriter<W> {
+        CrcWriter { inner, hasher: crc32fast::Hasher::new() }
+    }
+
+    fn crc(&self) -> u32 {
+        self.hasher.clone().finalize()
+    }
+}
+
+impl<W: Write> Write for CrcWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Normalizes separators (and optionally case) of an entry name; a file whose name collides,
+/// case-insensitively, with one already unpacked gets a numeric suffix instead of overwriting it.
+fn normalize_entry_name(name: &str, is_dir: bool, lowercase: bool, seen: &mut HashSet<String>) -> String {
+    let mut normalized = name.replace('\\', "/");
+    if lowercase {
+        normalized = normalized.to_lowercase();
+    }
+    if is_dir {
+        return normalized;
+    }
+    let mut candidate = normalized.clone();
+    let mut counter = 1;
+    while !seen.insert(candidate.to_lowercase()) {
+        let path = Path::new(&normalized);
+        let stem = path.file_stem().map_or_else(String::new, |stem| stem.to_string_lossy().to_string());
+        let renamed = match path.extension() {
+            Some(extension) => format!("{}-{}.{}", stem, counter, extension.to_string_lossy()),
+            None => format!("{}-{}", stem, counter),
+        };
+        candidate = path.with_file_name(renamed).to_string_lossy().to_string();
+        counter += 1;
+    }
+    if candidate != normalized {
+        warn!("entry {:?} collides with an already unpacked entry, unpacking it as {:?}", name, candidate);
+    }
+    candidate
+}
+
+/// Joins an archive entry to the extraction root, refusing anything that could land outside of it
+/// (`..` components, absolute paths, drive prefixes), whatever the zip crate already accepted.
+fn enclosed_path(root: &Path, entry_name: &str, out_path: &Path) -> Result<PathBuf> {
+    let raw_escapes = entry_name.starts_with('/') || entry_name.starts_with('\\')
+        || entry_name.split(['/', '\\']).any(|part| part == "..");
+    let component_escapes = out_path.components()
+        .any(|component| !matches!(component, Component::Normal(_) | Component::CurDir));
+    let full_path = root.join(out_path);
+    if raw_escapes || component_escapes || !full_path.starts_with(root) {
+        bail!("archive entry {:?} would be extracted outside of {:?}, refusing", entry_name, root);
+    }
+    Ok(full_path)
+}
+
+fn process_files(source: &dyn AsRef<Path>, target: &Path, opts: &Opts, update_base: Option<&UpdateBase>,
+                 page_options: &HashMap<String, PageOptions>) -> Result<ArchiveStats> {
+    let mut files = vec![];
+    let walker = globwalk::GlobWalkerBuilder::from_patterns(
+        source,
+        &[ "**/*" ],
+    )
+    .file_type(GlobFileType::FILE | GlobFileType::DIR)
+    .contents_first(false) // directory before content
+    .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+    .build()?
+    .filter_map(Result::ok);
+
+    let mut junk = 0;
+    for entry in walker {
+        let entry_type = entry.file_type();
+        debug!("{:?} type {:?}", entry, entry_type);
+        if entry_type.is_file() && entry.file_name() == MARKER_NAME {
+            // a --reprocess source's marker, replaced by the one of this run if any
+            debug!("dropping previous marker {:?}", entry.path());
+        } else if entry_type.is_file() && opts.embed_report && entry.path().strip_prefix(source)? == Path::new(&opts.embed_report_name) {
+            debug!("dropping previous embedded report {:?}", entry.path());
+        } else if entry_type.is_file() && entry.file_name() == PAGES_NAME {
+            debug!("dropping pages sidecar {:?}", entry.path());
+        } else if !opts.no_default_excludes && is_junk(entry.path().strip_prefix(source)?) {
+            if entry_type.is_file() {
+                debug!("dropping file manager junk {:?}", entry.path());
+                junk += 1;
+            }
+        } else if entry_type.is_dir() {
+            // create dir in destination
+            let path = entry.path();
+            match path.absolutize() {
+                Ok(canon) => {
+                    info!("create directory {:?}", canon);
+                    create_dir_all(canon)?
+                },
+                Err(error) => {
+                    warn!("couldn't absolutize path {:?} - {:?}", entry, error);
+                }
+            }
+        } else if entry_type.is_file() {
+            match entry.path().absolutize() {
+                Ok(canon) => files.push(canon.to_path_buf()),
+                Err(error) => {
+                    warn!("couldn't absolutize path {:?} - {:?}", entry, error);
+                }
+            }
+        } else {
+            warn!("{:?} is not a file or directory, skipping", entry);
+        }
+
+    }
+
+    let mut stats = process_listed_files(&files, source.as_ref(), target, opts, update_base, page_options)?;
+    stats.junk = junk;
+    if stats.junk > 0 {
+        info!("{} file manager junk files dropped", stats.junk);
+    }
+    debug!("{:?}", stats);
+    Ok(stats)
+}
+
+/// Processes `files`, all below `source`, into `target` with --jobs workers: the part of
+/// process_files shared with --glob, where `files` are the matches of the pattern.
+fn process_listed_files(files: &[PathBuf], source: &Path, target: &Path, opts: &Opts, update_base: Option<&UpdateBase>,
+                        page_options: &HashMap<String, PageOptions>) -> Result<ArchiveStats> {
+    let jobs = if opts.jobs == "auto" { auto_jobs(files) } else { job_count(opts)? };
+    let cover = (opts.cover_geometry.is_some() || opts.cover_quality.is_some())
+        .then(|| files.iter().find(|file| is_image(file)))
+        .flatten();
+    let cover_opts = cover_opts(opts);
+    let aggressive = match opts.aggressive_above_percentile {
+        Some(percentile) => outlier_images(files, source, percentile)?,
+        None => HashSet::new(),
+    };
+    let aggressive_opts = Opts {
+        quality: opts.aggressive_quality.clone(),
+        quality_for: vec![],
+        target_ssim: None,
+        ..opts.clone()
+    };
+    let in_range = opts.page_range.as_deref().and_then(page_range).map(|(start, end)| {
+        let images: Vec<&PathBuf> = files.iter().filter(|file| is_image(file)).collect();
+        if start > images.len() {
+            warn!("--page-range starts at {} but {} only has {} images", start, opts.source, images.len());
+        }
+        (1..).zip(images)
+            .filter(|(page, _)| *page >= start && end.is_none_or(|end| *page <= end))
+            .map(|(_, file)| file)
+            .collect::<HashSet<_>>()
+    });
+    let outside_opts = Opts {
+        quality: Some("copy".to_string()),
+        quality_for: vec![],
+        target_ssim: None,
+        ..opts.clone()
+    };
+    // workers pick the next file in order, each one keeping its own stats
+    let names: HashSet<_> = files.iter().map(|file| entry_name(file.strip_prefix(source).unwrap_or(file))).collect();
+    for name in page_options.keys().filter(|name| !names.contains(*name)) {
+        warn!("the pages sidecar has settings for {:?}, which isn't in {}", name, opts.source);
+    }
+    let next = Mutex::new(files.iter());
+    let worker_stats = thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs.min(files.len()).max(1)).map(|_| scope.spawn(|| {
+            let mut stats = ArchiveStats::default();
+            loop {
+                let Some(file) = next.lock().unwrap().next() else { break };
+                let outside = is_image(file) && in_range.as_ref().is_some_and(|in_range| !in_range.contains(file));
+                if outside && opts.page_range_others == "drop" {
+                    debug!("{:?} is outside --page-range, dropping it", file);
+                    stats.outside_range += 1;
+                    continue;
+                }
+                let opts = if outside {
+                    debug!("{:?} is outside --page-range, copying it", file);
+                    &outside_opts
+                } else if cover == Some(file) {
+                    debug!("{:?} is the cover", file);
+                    &cover_opts
+                } else if aggressive.contains(file) {
+                    &aggressive_opts
+                } else {
+                    opts
+                };
+                let page = page_options.get(&entry_name(file.strip_prefix(source).unwrap_or(file))).filter(|_| !outside);
+                let page_opts = page.map(|page| {
+                    debug!("{:?} has its own settings {:?}", file, page);
+                    page.apply(opts)
+                });
+                let opts = page_opts.as_ref().unwrap_or(opts);
+                // the dates --update goes by don't tell whether the page's settings changed
+                let update_base = update_base.filter(|_| page.is_none() || opts.content_cache);
+                if let Err(error) = process_one_file(file, source, target, opts, update_base, &mut stats) {
+                    error!("{}", error);
+                    stats.failed += 1;
+                    stats.failures.push(file.strip_prefix(source).unwrap_or(file).to_path_buf());
+                    // continue with other files
+                }
+            }
+            stats
+        })).collect();
+        workers.into_iter().map(|worker| worker.join().expect("worker panicked")).collect::<Vec<_>>()
+    });
+    let mut stats = ArchiveStats::default();
+    for worker in worker_stats {
+        stats.merge(worker);
+    }
+    if stats.empty > 0 {
+        info!("{} {}", stats.empty, if opts.drop_empty { "empty files dropped" } else { "empty images found" });
+    }
+    if let Some(format) = &opts.normalize_format {
+        info!("{} images transcoded to {}", stats.transcoded, format);
+    }
+    if opts.images_only {
+        info!("{} non-image files dropped", stats.dropped);
+    }
+    if stats.outside_range > 0 {
+        info!("{} images outside --page-range dropped", stats.outside_range);
+    }
+    if update_base.is_some() {
+        info!("{} images up to date, {} converted", stats.reused, stats.images);
+    }
+    Ok(stats)
+}
+
+/// The images bigger than the `percentile` of all image sizes (nearest rank), which
+/// --aggressive-above-percentile converts at a lower quality; they are logged as they're picked.
+fn outlier_images(files: &[PathBuf], source: &Path, percentile: u8) -> Result<HashSet<PathBuf>> {
+    let mut sizes = vec![];
+    for file in files.iter().filter(|file| is_image(file)) {
+        sizes.push((file.metadata()?.len(), file));
+    }
+    if sizes.is_empty() {
+        return Ok(HashSet::new());
+    }
+    let mut sorted: Vec<u64> = sizes.iter().map(|(size, _)| *size).collect();
+    sorted.sort_unstable();
+    let rank = (sorted.len() * percentile as usize).div_ceil(100).max(1);
+    let threshold = sorted[rank - 1];
+    let outliers: HashSet<PathBuf> = sizes.into_iter()
+        .filter(|(size, _)| *size > threshold)
+        .map(|(_, file)| file.clone())
+        .collect();
+    info!("{} of {} images above the {}th percentile ({} bytes), converting them with --aggressive-quality",
+        outliers.len(), sorted.len(), percentile, threshold);
+    let mut listed: Vec<_> = outliers.iter().collect();
+    listed.sort();
+    for outlier in listed {
+        info!("aggressive: {:?}", outlier.strip_prefix(source).unwrap_or(outlier));
+    }
+    Ok(outliers)
+}
+
+/// Options for the cover: --cover-geometry and --cover-quality replace the ones of the other pages.
+fn cover_opts(opts: &Opts) -> Opts {
+    let mut cover = opts.clone();
+    if let Some(geometry) = &opts.cover_geometry {
+        cover.geometry = Some(geometry.clone());
+        cover.max_width = None;
+        cover.max_height = None;
+    }
+    if let Some(quality) = &opts.cover_quality {
+        cover.quality = Some(quality.clone());
+        cover.quality_for = vec![];
+        cover.target_ssim = None;
+    }
+    cover
+}
+
+/// The pages sidecar of an archive, by entry path: the `.img-optim-pages.json` entry of the source,
+/// overridden page by page by a `<source>.img-optim-pages.json` file beside it.
+fn load_page_options(source_path: &Path, unpack_dir: &Path, opts: &Opts) -> Result<HashMap<String, PageOptions>> {
+    let mut beside = source_path.as_os_str().to_owned();
+    beside.push(PAGES_NAME);
+    let mut candidates = vec![unpack_dir.join(PAGES_NAME)];
+    if !opts.merge && opts.source != "-" {
+        candidates.push(PathBuf::from(beside));
+    }
+    let mut pages = HashMap::new();
+    for candidate in candidates.iter().filter(|candidate| candidate.is_file()) {
+        let read: HashMap<String, PageOptions> = serde_json::from_slice(&std::fs::read(candidate)?)
+            .map_err(|error| anyhow!("invalid pages sidecar {:?}: {}", candidate, error))?;
+        info!("{} pages with their own settings in {:?}", read.len(), candidate);
+        for page in read.values() {
+            if let Some(extension) = &page.extension {
+                check_output_extension(extension, opts)?;
+            }
+        }
+        pages.extend(read);
+    }
+    Ok(pages)
+}
+
+/// Size of each image below `dir`, by entry name, file manager junk left out.
+fn source_sizes(dir: &Path, opts: &Opts) -> Result<BTreeMap<String, u64>> {
+    let mut sizes = BTreeMap::new();
+    for sub_path in list_entries(dir)? {
+        let path = dir.join(&sub_path);
+        if is_image(&sub_path) && path.is_file() && (opts.no_default_excludes || !is_junk(&sub_path)) {
+            sizes.insert(entry_name(&sub_path), path.metadata()?.len());
+        }
+    }
+    Ok(sizes)
+}
+
+/// Records the source image sizes of `source` in the --baseline file on its first run, or
+/// reports how they drifted from the recorded ones; returns the total size of the baseline.
+fn check_baseline(baseline_path: &Path, source: &str, unpack_dir: &Path, opts: &Opts) -> Result<u64> {
+    let mut baseline = Baseline::load(baseline_path)?;
+    let sizes = source_sizes(unpack_dir, opts)?;
+    let recorded = match baseline.archives.get(source) {
+        Some(recorded) => recorded,
+        None => {
+            let total = sizes.values().sum();
+            info!("baseline of {} recorded: {} images, {} bytes", source, sizes.len(), total);
+            let recorded = humantime::format_rfc3339_seconds(SystemTime::now()).to_string();
+            baseline.archives.insert(source.to_string(), BaselineArchive { recorded, sizes });
+            baseline.save(baseline_path)?;
+            return Ok(total);
+        }
+    };
+    let changed: Vec<&String> = sizes.iter()
+        .filter(|(entry, size)| recorded.sizes.get(*entry).is_some_and(|before| before != *size))
+        .map(|(entry, _)| entry)
+        .collect();
+    let added: Vec<&String> = sizes.keys().filter(|entry| !recorded.sizes.contains_key(*entry)).collect();
+    let gone: Vec<&String> = recorded.sizes.keys().filter(|entry| !sizes.contains_key(*entry)).collect();
+    let total = recorded.sizes.values().sum();
+    if changed.is_empty() && added.is_empty() && gone.is_empty() {
+        info!("{} matches its baseline of {}", source, recorded.recorded);
+    } else {
+        debug!("changed since the baseline: {:?}, new: {:?}, gone: {:?}", changed, added, gone);
+        warn!("{} drifted from its baseline of {}: {} images changed, {} new, {} gone, {} bytes now against {}",
+            source, recorded.recorded, changed.len(), added.len(), gone.len(), sizes.values().sum::<u64>(), total);
+    }
+    Ok(total)
+}
+
+/// Number of source images per format, `jpeg` counted as `jpg` and `tif` as `tiff`.
+fn page_formats(unpack_dir: &Path, opts: &Opts) -> Result<BTreeMap<String, usize>> {
+    let mut formats = BTreeMap::new();
+    for sub_path in list_entries(unpack_dir)? {
+        if !is_image(&sub_path) || (!opts.no_default_excludes && is_junk(&sub_path)) || !unpack_dir.join(&sub_path).is_file() {
+            continue;
+        }
+        let format = match sub_path.extension().unwrap_or_default().to_string_lossy().to_lowercase().as_str() {
+            "jpeg" => "jpg".to_string(),
+            "tif" => "tiff".to_string(),
+            other => other.to_string(),
+        };
+        *formats.entry(format).or_insert(0) += 1;
+    }
+    Ok(formats)
+}
+
+/// Whether a path below the unpack directory is, or is inside, one of the DEFAULT_EXCLUDES.
+fn is_junk(path: &Path) -> bool {
+    path.components().any(|component| DEFAULT_EXCLUDES.iter()
+        .any(|junk| component.as_os_str().to_str().is_some_and(|name| name.eq_ignore_ascii_case(junk))))
+}
+
+/// Worker count for `--jobs auto`: the CPU count, lowered so that the decode buffers of the
+/// largest image, one per worker, stay under half of the available memory.
+fn auto_jobs(files: &[PathBuf]) -> usize {
+    let cpus = thread::available_parallelism().map_or(1, |count| count.get());
+    let largest = files.iter()
+        .filter(|file| is_image(file))
+        .filter_map(|file| image_dimensions(file).ok())
+        .map(|(width, height)| width as u64 * height as u64)
+        .max();
+    let (Some(pixels), Some(available)) = (largest, available_memory()) else {
+        info!("--jobs auto: {} workers, one per CPU (no image size or available memory to go by)", cpus);
+        return cpus;
+    };
+    // gm works on 16 bits per channel RGBA pixels, and keeps the decoded and resized images around
+    let per_image = (pixels * 8 * 2).max(1);
+    let jobs = ((available / 2 / per_image) as usize).clamp(1, cpus);
+    info!("--jobs auto: {} workers ({} CPUs, {} MiB available, largest image {} Mpx needing ~{} MiB)",
+        jobs, cpus, available >> 20, pixels / 1_000_000, per_image >> 20);
+    jobs
+}
+
+/// `MemAvailable` from /proc/meminfo, in bytes.
+fn available_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|line| line.starts_with("MemAvailable:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
+/// Counting semaphore bounding how many `gm` processes run at the same time.
+struct Semaphore {
+    state: Mutex<SemaphoreState>,
+    released: Condvar,
+}
+
+struct SemaphoreState {
+    in_use: usize,
+    limit: Option<usize>,
+}
+
+struct SemaphoreGuard<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl Semaphore {
+    fn new() -> Semaphore {
+        Semaphore {
+            state: Mutex::new(SemaphoreState { in_use: 0, limit: None }),
+            released: Condvar::new(),
+        }
+    }
+
+    fn set_limit(&self, limit: usize) {
+        self.state.lock().unwrap().limit = Some(limit);
+        self.released.notify_all();
+    }
+
+    fn acquire(&self) -> SemaphoreGuard<'_> {
+        let mut state = self.state.lock().unwrap();
+        while state.limit.is_some_and(|limit| state.in_use >= limit) {
+            state = self.released.wait(state).unwrap();
+        }
+        state.in_use += 1;
+        SemaphoreGuard { semaphore: self }
+    }
+}
+
+impl Drop for SemaphoreGuard<'_> {
+    fn drop(&mut self) {
+        self.semaphore.state.lock().unwrap().in_use -= 1;
+        self.semaphore.released.notify_one();
+    }
+}
+
+lazy_static! {
+    static ref GM_PROCESSES: Semaphore = Semaphore::new();
+}
+
+lazy_static! {
+    static ref NESTED_ARCHIVE_EXTENSIONS: Vec<&'static str> = vec!["zip", "cbz"];
+}
+
+lazy_static! {
+    static ref IMAGE_EXTENSIONS: Vec<&'static str> = vec!["jpg","jpeg", "png", "webp", "avif", "gif"];
+}
+
+fn process_one_file(item: &Path, source: &Path, target: &Path, opts: &Opts, update_base: Option<&UpdateBase>, stats: &mut ArchiveStats) -> Result<()> {
+    let is_empty = item.metadata()?.len() == 0;
+    if is_empty && opts.drop_empty {
+        debug!("{:?} is empty, dropping it", item);
+        stats.empty += 1;
+        return Ok(());
+    }
+    let extension = item.extension()
+                .map_or_else(
+                    || "".to_string(),
+                    |ext| ext.to_str().unwrap_or("").to_string()
+                );
+    let sniffed = if opts.sniff && !opts.copy_only { sniffed_extension(item)? } else { None };
+    if let Some(sniffed) = sniffed.filter(|sniffed| !sniffed.eq_ignore_ascii_case(&extension)) {
+        debug!("{:?} is actually a {} image", item, sniffed);
+    }
+    if !opts.copy_only && (sniffed.is_some() || IMAGE_EXTENSIONS.contains(&extension.as_str().to_lowercase().as_str())) {
+        stats.found += 1;
+        if is_empty {
+            stats.empty += 1;
+            if opts.empty_policy == "error" {
+                bail!("{:?} is an empty image (0 bytes) gm can't convert; use --empty-policy skip to copy it or --drop-empty to drop it", item);
+            }
+            warn!("{:?} is an empty image (0 bytes), copying it unchanged", item);
+            copy_one_file(item, source, target)?;
+            stats.copied += 1;
+            return Ok(());
+        }
+        let sha256 = if opts.content_cache { Some(sha256_file(item)?) } else { None };
+        let reused = match (update_base, &sha256) {
+            (Some(base), Some(sha256)) => base.reuse_cached(item.strip_prefix(source)?, sha256, target)?,
+            (Some(base), None) => base.reuse(item, source, target, opts)?,
+            (None, _) => None,
+        };
+        if let Some(reused) = reused {
+            debug!("{:?} is unchanged, keeping {:?}", item, reused);
+            stats.outputs += reused.len();
+            stats.reused += 1;
+            record_cached(item, source, target, sha256, &reused, stats)?;
+            return Ok(());
+        }
+        if let Some(min_dimension) = opts.min_dimension {
+            let (width, height) = image_dimensions(item)?;
+            if width.max(height) < min_dimension {
+                info!("{:?} is {}x{}, below --min-dimension {}, copying unchanged", item, width, height, min_dimension);
+                copy_one_file(item, source, target)?;
+                stats.copied += 1;
+                return Ok(());
+            }
+        }
+        if let Some(max_aspect_ratio) = opts.max_aspect_ratio {
+            let (width, height) = image_dimensions(item)?;
+            let ratio = width.max(height) as f64 / width.min(height).max(1) as f64;
+            if ratio > max_aspect_ratio {
+                if opts.aspect_policy == "error" {
+                    bail!("{:?} is {}x{}, an aspect ratio of {:.1} over --max-aspect-ratio {}: malformed scan?",
+                        item, width, height, ratio, max_aspect_ratio);
+                }
+                warn!("{:?} is {}x{}, an aspect ratio of {:.1} over --max-aspect-ratio {}: malformed scan? copying it unchanged",
+                    item, width, height, ratio, max_aspect_ratio);
+                copy_one_file(item, source, target)?;
+                stats.copied += 1;
+                return Ok(());
+            }
+        }
+        let produced = process_one_image(item, source, target, opts, sniffed, stats)?;
+        record_cached(item, source, target, sha256, &produced, stats)
+    } else if !opts.copy_only && NESTED_ARCHIVE_EXTENSIONS.contains(&extension.as_str().to_lowercase().as_str()) {
+        process_nested_archive(item, source, target, opts, stats)
+    } else if opts.images_only {
+        debug!("{:?} is not an image, dropping it", item);
+        stats.dropped += 1;
+        Ok(())
+    } else {
+        copy_one_file(item, source, target)?;
+        stats.copied += 1;
+        Ok(())
+    }
+}
+
+fn record_cached(item: &Path, source: &Path, target: &Path, sha256: Option<String>, outputs: &[PathBuf], stats: &mut ArchiveStats) -> Result<()> {
+    if let Some(sha256) = sha256 {
+        let outputs = outputs.iter()
+            .map(|output| Ok(entry_name(output.strip_prefix(target)?)))
+            .collect::<Result<Vec<_>>>()?;
+        stats.cached.insert(entry_name(item.strip_prefix(source)?), CachedImage { sha256, outputs });
+    }
+    Ok(())
+}
+
+/// Extension of the image format found in the header of `item`, if it is one img-optim handles.
+fn sniffed_extension(item: &Path) -> Result<Option<&'static str>> {
+    let mut header = Vec::with_capacity(4096);
+    std::fs::File::open(item)?.take(4096).read_to_end(&mut header)?;
+    Ok(match imagesize::image_type(&header) {
+        Ok(imagesize::ImageType::Jpeg) => Some("jpg"),
+        Ok(imagesize::ImageType::Png) => Some("png"),
+        Ok(imagesize::ImageType::Gif) => Some("gif"),
+        Ok(imagesize::ImageType::Webp) => Some("webp"),
+        Ok(imagesize::ImageType::Heif(imagesize::Compression::Av1)) => Some("avif"),
+        _ => None,
+    })
+}
+
+/// Reads the image size from the file header, without decoding the image.
+fn image_dimensions(item: &Path) -> Result<(u32, u32)> {
+    let size = imagesize::size(item)
+        .map_err(|error| anyhow!("couldn't read dimensions of {:?}: {}", item, error))?;
+    Ok((size.width as u32, size.height as u32))
+}
+
+fn process_nested_archive(item: &Path, source: &Path, target: &Path, opts: &Opts, stats: &mut ArchiveStats) -> Result<()> {
+    if opts.depth >= opts.max_depth {
+        warn!("{:?} is nested deeper than --max-depth {}, copying it unchanged", item, opts.max_depth);
+        copy_one_file(item, source, target)?;
+        stats.copied += 1;
+        return Ok(());
+    }
+    let destination = target.join(item.strip_prefix(source)?);
+    create_parent(&destination)?;
+    info!("processing nested archive {:?}", item);
+    let nested = Opts {
+        source: item.to_string_lossy().to_string(),
+        target: destination.to_string_lossy().to_string(),
+        many: None,
+        merge: false,
+        no_repack: None,
+        manifest: None,
+        baseline: None,
+        update: false,
+        content_cache: false,
+        split_size: None,
+        // the marker belongs to the outer archive, a nested one is converted as part of it
+        marker: false,
+        embed_report: false,
+        contact_sheet: None,
+        compare: None,
+        // its pages are numbered from 1 again, the range is the outer archive's
+        page_range: None,
+        reprocess: true,
+        overwrite: Some("overwrite".to_string()),
+        depth: opts.depth + 1,
+        ..opts.clone()
+    };
+    process_archive(&nested)?;
+    stats.nested += 1;
+    Ok(())
+}
+
+fn copy_one_file(item: &Path, source: &Path, target: &Path) -> Result<PathBuf> {
+    let sub_path = item.strip_prefix(source)?;
+    let destination = target.join(sub_path);
+    create_parent(&destination)?;
+    std::fs::copy(item, &destination)?;
+    Ok(destination)
+}
+
+/// Quality for one source image: a matching --quality-for wins over --quality.
+fn image_quality(extension: &str, opts: &Opts) -> Result<String> {
+    for spec in &opts.quality_for {
+        match spec.split_once('=') {
+            Some((ext, quality)) if ext.trim_start_matches('.').eq_ignore_ascii_case(extension) => {
+                return Ok(quality.to_string());
+            }
+            Some(_) => {}
+            None => bail!("invalid --quality-for '{}', expected `EXT=QUALITY`", spec),
+        }
+    }
+    Ok(opts.quality.clone().unwrap_or_else(|| default_quality(opts)))
+}
+
+/// Quality used when --quality isn't given: --format-quality, the built-in default of the output format, or 80.
+fn default_quality(opts: &Opts) -> String {
+    let extension = output_extension(opts);
+    opts.format_quality.iter()
+        .filter_map(|spec| spec.split_once('='))
+        .map(|(ext, quality)| (ext.trim_start_matches('.'), quality))
+        .chain(FORMAT_QUALITIES.iter().copied())
+        .find(|(ext, _)| ext.eq_ignore_ascii_case(extension))
+        .map_or_else(|| DEFAULT_QUALITY.to_string(), |(_, quality)| quality.to_string())
+}
+
+fn parse_format_quality(spec: &str) -> Result<String, String> {
+    match spec.split_once('=') {
+        Some((ext, quality)) if !ext.is_empty() && !quality.is_empty() => Ok(spec.to_string()),
+        _ => Err(format!("expected `EXT=QUALITY`, got '{}'", spec)),
+    }
+}
+
+/// First and last page of a --page-range, the last one `None` when open-ended.
+fn page_range(spec: &str) -> Option<(usize, Option<usize>)> {
+    let (start, end) = spec.split_once(':')?;
+    let start = if start.is_empty() { 1 } else { start.parse().ok()? };
+    let end = if end.is_empty() { None } else { Some(end.parse().ok()?) };
+    (start >= 1 && end.is_none_or(|end| end >= start)).then_some((start, end))
+}
+
+fn parse_page_range(spec: &str) -> Result<String, String> {
+    match page_range(spec) {
+        Some(_) => Ok(spec.to_string()),
+        None => Err(format!("expected `START:END` with 1 <= START <= END, either one possibly left out, got '{}'", spec)),
+    }
+}
+
+fn parse_gm_env(variable: &str) -> Result<String, String> {
+    match variable.split_once('=') {
+        Some((key, _)) if !key.is_empty() && !key.contains(char::is_whitespace) => Ok(variable.to_string()),
+        _ => Err(format!("expected `KEY=VALUE`, got '{}'", variable)),
+    }
+}
+
+fn parse_crop_to(spec: &str) -> Result<String, String> {
+    match spec.split_once('x').map(|(width, height)| (width.parse::<u32>(), height.parse::<u32>())) {
+        Some((Ok(width), Ok(height))) if width > 0 && height > 0 => Ok(spec.to_string()),
+        _ => Err(format!("expected `WxH` in pixels, got '{}'", spec)),
+    }
+}
+
+fn parse_debug_image(pattern: &str) -> Result<String, String> {
+    globset::Glob::new(pattern).map(|_| pattern.to_string()).map_err(|error| error.to_string())
+}
+
+fn parse_contrast_stretch(spec: &str) -> Result<String, String> {
+    let bounds = spec.split_once(['x', 'X'])
+        .and_then(|(black, white)| Some((black.trim_end_matches('%').parse::<f64>().ok()?, white.trim_end_matches('%').parse::<f64>().ok()?)));
+    match bounds {
+        Some((black, white)) if black >= 0.0 && white >= 0.0 && black + white < 100.0 => Ok(spec.to_string()),
+        _ => Err(format!("expected `BLACKxWHITE` percentages adding up to less than 100, got '{}'", spec)),
+    }
+}
+
+fn parse_png_quality(spec: &str) -> Result<String, String> {
+    let bounds = spec.split_once('-')
+        .and_then(|(min, max)| Some((min.parse::<u8>().ok()?, max.parse::<u8>().ok()?)));
+    match bounds {
+        Some((min, max)) if min <= max && max <= 100 => Ok(spec.to_string()),
+        _ => Err(format!("expected `MIN-MAX` with 0 <= MIN <= MAX <= 100, got '{}'", spec)),
+    }
+}
+
+/// Geometry applied to every image: --max-width/--max-height give a downscale-only
+/// bounding box (either side may be left free), otherwise --geometry or the default.
+fn geometry(opts: &Opts) -> String {
+    let geometry = match (opts.max_width, opts.max_height) {
+        (None, None) => opts.geometry.as_deref().unwrap_or(DEFAULT_GEOMETRY).to_string(),
+        (width, height) => format!("{}x{}>",
+            width.map_or_else(String::new, |width| width.to_string()),
+            height.map_or_else(String::new, |height| height.to_string())),
+    };
+    // --max-width/--max-height keep their `>`, the result never being enlarged
+    let shrink_only = if opts.max_width.is_some() || opts.max_height.is_some() { ">" } else { "" };
+    let size = geometry.trim_end_matches(GEOMETRY_MODIFIERS);
+    match opts.fit.as_deref() {
+        Some("contain") => format!("{}{}", size, shrink_only),
+        Some("cover") => format!("{}^{}", size, shrink_only),
+        Some("stretch") => format!("{}!{}", size, shrink_only),
+        _ => geometry,
+    }
+}
+
+/// Region kept after the resize, placed by --crop-gravity: --crop-to, or the `WxH` of the geometry with `--fit cover`.
+fn fit_crop(opts: &Opts) -> Option<String> {
+    if let Some(crop_to) = &opts.crop_to {
+        return Some(format!("{}+0+0", crop_to));
+    }
+    match (opts.fit.as_deref(), geometry_size(&geometry(opts))) {
+        (Some("cover"), Some((Some(width), Some(height)))) => Some(format!("{}x{}+0+0", width, height)),
+        _ => None,
+    }
+}
+
+/// Size of a `width`x`height` image once resized by gm to a `WxH` geometry and its modifiers, `None` for other forms.
+fn resized_size((width, height): (u32, u32), geometry: &str) -> Option<(u32, u32)> {
+    let (box_width, box_height) = geometry_size(geometry)?;
+    let modifiers = &geometry[geometry.trim_end_matches(GEOMETRY_MODIFIERS).len()..];
+    if let (true, Some(box_width), Some(box_height)) = (modifiers.contains('!'), box_width, box_height) {
+        return Some((box_width, box_height));
+    }
+    let scales = [box_width.map(|box_width| box_width as f64 / width as f64), box_height.map(|box_height| box_height as f64 / height as f64)];
+    let scales = scales.iter().flatten().copied();
+    let mut scale = if modifiers.contains('^') { scales.fold(f64::MIN, f64::max) } else { scales.fold(f64::MAX, f64::min) };
+    if (modifiers.contains('>') && scale > 1.0) || (modifiers.contains('<') && scale < 1.0) || scale == f64::MIN || scale == f64::MAX {
+        scale = 1.0;
+    }
+    Some(((width as f64 * scale).round() as u32, (height as f64 * scale).round() as u32))
+}
+
+/// Width and height of a `WxH` geometry, either one possibly missing; `None` for other forms (`50%`, `WxH+X+Y`...).
+fn geometry_size(geometry: &str) -> Option<(Option<u32>, Option<u32>)> {
+    let (width, height) = geometry.trim_end_matches(GEOMETRY_MODIFIERS).split_once(['x', 'X'])?;
+    let parse = |value: &str| if value.is_empty() { Some(None) } else { value.parse().ok().map(Some) };
+    Some((parse(width)?, parse(height)?))
+}
+
+/// Fails early on a --fit the geometry can't take: it needs a `WxH` box, complete for `cover` and `stretch`.
+fn check_fit(opts: &Opts) -> Result<()> {
+    let fit = match &opts.fit {
+        Some(fit) => fit,
+        None => return Ok(()),
+    };
+    for (name, opts) in [("geometry", opts), ("cover geometry", &cover_opts(opts))] {
+        let geometry = geometry(&Opts { fit: None, ..opts.clone() });
+        match geometry_size(&geometry) {
+            Some((Some(_), Some(_))) => {}
+            Some((Some(_), None)) | Some((None, Some(_))) if fit == "contain" => {}
+            Some(_) => bail!("--fit {} needs both a width and a height, the {} is {}", fit, name, geometry),
+            None => bail!("--fit needs a `WxH` geometry, the {} is {}", name, geometry),
+        }
+    }
+    Ok(())
+}
+
+/// Fails early on a --crop-gravity without a crop to place.
+fn check_crop_gravity(opts: &Opts) -> Result<()> {
+    if opts.crop_gravity.is_some() && opts.crop_to.is_none() && opts.fit.as_deref() != Some("cover") {
+        bail!("--crop-gravity needs --crop-to or --fit cover");
+    }
+    Ok(())
+}
+
+fn output_extension(opts: &Opts) -> &str {
+    opts.normalize_format.as_deref()
+        .or(opts.extension.as_deref())
+        .unwrap_or(DEFAULT_EXTENSION)
+}
+
+/// Number of frames in an image, as reported by `gm identify` (one line per frame).
+fn frame_count(item: &Path, opts: &Opts) -> Result<usize> {
+    let output = run_gm(gm_command(opts).arg("identify").arg(item), opts.image_timeout)?;
+    if !output.status.success() {
+        bail!("`gm identify` invocation failed\n{}\n", String::from_utf8_lossy(&output.stderr));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).lines().count())
+}
+
+/// Whether --rotate-exif-only can skip gm for this image: a JPEG staying a JPEG, with no
+/// transform requested that only gm applies (explicit size or quality, crop, rotation, colors...).
+fn is_orientation_only(item: &Path, source_extension: &str, output_extension: &str, opts: &Opts) -> Result<bool> {
+    let is_jpeg = |extension: &str| extension.eq_ignore_ascii_case("jpg") || extension.eq_ignore_ascii_case("jpeg");
+    let other_transforms = opts.geometry.is_some() || opts.max_width.is_some() || opts.max_height.is_some()
+        || opts.quality.is_some() || !opts.quality_for.is_empty() || opts.target_ssim.is_some() || !opts.sizes.is_empty()
+        || !opts.format_quality.is_empty() || opts.subsampling.is_some() || opts.bit_depth.is_some()
+        || opts.trim || opts.rotate.is_some() || opts.auto_rotate_landscape || opts.split_spreads
+        || opts.colors.is_some() || opts.define.is_some() || opts.set_dpi.is_some() || opts.strip_dpi
+        || opts.normalize_contrast || opts.contrast_stretch.is_some() || opts.fit.is_some() || opts.crop_to.is_some()
+        || !opts.gm_arg.is_empty();
+    if !is_jpeg(source_extension) || !is_jpeg(output_extension) || other_transforms {
+        return Ok(false);
+    }
+    Ok(!(opts.force_rgb && is_cmyk(item, opts)?))
+}
+
+/// Writes `item` to `result` upright, turned by `jpegtran` as its EXIF orientation says, with the
+/// orientation then reset to normal; an upright image is copied. Returns false when the turn can't
+/// be lossless (size not a multiple of the JPEG block), leaving the image to gm.
+fn rotate_losslessly(item: &Path, result: &Path) -> Result<bool> {
+    let transform: &[&str] = match exif_orientation(item)? {
+        Some(2) => &["-flip", "horizontal"],
+        Some(3) => &["-rotate", "180"],
+        Some(4) => &["-flip", "vertical"],
+        Some(5) => &["-transpose"],
+        Some(6) => &["-rotate", "90"],
+        Some(7) => &["-transverse"],
+        Some(8) => &["-rotate", "270"],
+        _ => {
+            debug!("{:?} is upright, copying it", item);
+            std::fs::copy(item, result)?;
+            return Ok(true);
+        }
+    };
+    let mut command = Command::new("jpegtran");
+    command.args(["-copy", "all", "-perfect"]).args(transform).arg("-outfile").arg(result).arg(item);
+    debug!("Command: {:?}", command);
+    let output = command.output()?;
+    if !output.status.success() {
+        debug!("{:?} can't be turned losslessly, converting it with gm\n{}", item, String::from_utf8_lossy(&output.stderr));
+        return Ok(false);
+    }
+    reset_orientation(result)?;
+    Ok(true)
+}
+
+/// The EXIF orientation of `item` (1 to 8), if it has one.
+fn exif_orientation(item: &Path) -> Result<Option<u32>> {
+    let file = std::fs::File::open(item)?;
+    Ok(exif::Reader::new().read_from_container(&mut std::io::BufReader::new(file)).ok()
+        .and_then(|exif| exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY).and_then(|field| field.value.get_uint(0))))
+}
+
+/// Writes the animated GIF `item` to `result` with its frames optimized by gifsicle, which
+/// keeps the animation intact; `--colors` reduces the palette, no resize is done.
+fn optimize_gif(item: &Path, result: &Path, opts: &Opts) -> Result<()> {
+    let mut command = Command::new("gifsicle");
+    command.arg(format!("-O{}", opts.gif_optimize_level));
+    if let Some(lossy) = opts.gif_lossy {
+        command.arg(format!("--lossy={}", lossy));
+    }
+    if let Some(colors) = opts.colors {
+        command.arg("--colors").arg(colors.to_string());
+    }
+    command.arg(item).arg("-o").arg(result);
+    debug!("Command: {:?}", command);
+    let output = {
+        let _span = TRACER.span("encode", "gifsicle", &item.to_string_lossy());
+        command.output()?
+    };
+    if !output.status.success() {
+        bail!("`gifsicle` failed on {:?}\n{}\n", item, String::from_utf8_lossy(&output.stderr));
+    }
+    Ok(())
+}
+
+/// Whether `gm identify` reports a CMYK colorspace for the image.
+fn is_cmyk(item: &Path, opts: &Opts) -> Result<bool> {
+    let output = run_gm(gm_command(opts).arg("identify").arg("-format").arg("%r\\n").arg(item), opts.image_timeout)?;
+    if !output.status.success() {
+        bail!("`gm identify` invocation failed\n{}\n", String::from_utf8_lossy(&output.stderr));
+    }
+    // e.g. `DirectClassCMYK`, one line per frame
+    Ok(String::from_utf8_lossy(&output.stdout).lines().next().is_some_and(|class| class.contains("CMYK")))
+}
+
+/// `0` and `copy` are not gm qualities but mean "keep this image as it is".
+fn is_copy_quality(quality: &str) -> bool {
+    quality == "0" || quality.eq_ignore_ascii_case("copy")
+}
+
+/// Converts one image, returning the produced files: several with --sizes or --split-spreads.
+/// `sniffed` is the format read from the content with --sniff, which wins over the extension.
+fn process_one_image(item: &Path, source: &Path, target: &Path, opts: &Opts, sniffed: Option<&str>, stats: &mut ArchiveStats) -> Result<Vec<PathBuf>> {
+    let source_extension = sniffed.map_or_else(
+        || item.extension().map_or_else(String::new, |ext| ext.to_string_lossy().to_lowercase()),
+        str::to_string);
+    let quality = image_quality(&source_extension, opts)?;
+    if is_copy_quality(&quality) {
+        debug!("quality {} for {:?}, copying unchanged", quality, item);
+        let mut copied = copy_one_file(item, source, target)?;
+        if sniffed.is_some() && !has_extension(&copied, &source_extension) {
+            let renamed = copied.with_extension(&source_extension);
+            std::fs::rename(&copied, &renamed)?;
+            copied = renamed;
+        }
+        stats.copied += 1;
+        return Ok(vec![copied]);
+    }
+    let sub_path = item.strip_prefix(source)?;
+
+    let output_extension = if opts.png_lossy && source_extension == "png" { "png" } else { output_extension(opts) };
+    let result = target.join(sub_path)
+        .with_extension(output_extension);
+    create_parent(&result)?;
+
+    if opts.rotate_exif_only && is_orientation_only(item, &source_extension, output_extension, opts)? {
+        let started = Instant::now();
+        if rotate_losslessly(item, &result)? {
+            if opts.strip_gps {
+                strip_gps(&result)?;
+            }
+            stats.reports.push(FileReport {
+                file: sub_path.to_path_buf(),
+                outputs: result.strip_prefix(target).ok().map(Path::to_path_buf).into_iter().collect(),
+                duration_ms: started.elapsed().as_millis(),
+                backend: "jpegtran",
+            });
+            stats.conversions.push((item.to_path_buf(), vec![result.clone()]));
+            stats.outputs += 1;
+            stats.images += 1;
+            return Ok(vec![result]);
+        }
+    }
+
+    if opts.gifsicle && source_extension == "gif" && frame_count(item, opts)? > 1 {
+        let started = Instant::now();
+        let result = result.with_extension("gif");
+        optimize_gif(item, &result, opts)?;
+        stats.reports.push(FileReport {
+            file: sub_path.to_path_buf(),
+            outputs: result.strip_prefix(target).ok().map(Path::to_path_buf).into_iter().collect(),
+            duration_ms: started.elapsed().as_millis(),
+            backend: "gifsicle",
+        });
+        stats.conversions.push((item.to_path_buf(), vec![result.clone()]));
+        stats.outputs += 1;
+        stats.images += 1;
+        return Ok(vec![result]);
+    }
+
+    let debug_dir = match &opts.debug_image {
+        Some(pattern) if globset::Glob::new(pattern)?.compile_matcher().is_match(sub_path) => Some(keep_debug_source(item, sub_path)?),
+        _ => None,
+    };
+
+    let mut input = item.as_os_str().to_str().unwrap().to_string();
+    if opts.normalize_format.is_some() && source_extension == "gif" && !output_extension.eq_ignore_ascii_case("gif")
+        && frame_count(item, opts)? > 1 {
+        if opts.animated_gif.as_deref() == Some("error") {
+            bail!("{:?} is an animated GIF, refusing to transcode it to {}", item, output_extension);
+        }
+        debug!("{:?} is animated, keeping only its first frame", item);
+        input.push_str("[0]");
+    }
+
+    let to_rgb = opts.force_rgb && is_cmyk(item, opts)?;
+    if to_rgb {
+        debug!("{:?} is CMYK, converting it to RGB", item);
+    }
+
+    // gm turns a page stored sideways upright first, the size of the page as displayed is the one
+    // the crops, the rotation and the thresholds go by
+    let orientation = exif_orientation(item)?.filter(|orientation| *orientation > 1);
+    let auto_orient = orientation.is_some();
+    let dimensions = if opts.auto_rotate_landscape || opts.split_spreads || opts.resize_threshold.is_some() || opts.crop_to.is_some() {
+        let (width, height) = image_dimensions(item)?;
+        // 5 to 8 turn it a quarter
+        let sideways = orientation.is_some_and(|orientation| orientation >= 5);
+        Some(if sideways { (height, width) } else { (width, height) })
+    } else {
+        None
+    };
+    let is_landscape = dimensions.is_some_and(|(width, height)| width > height);
+
+    // a spread becomes two pages, `-1` and `-2` keep them sorted right where the spread was
+    let pieces = match dimensions {
+        Some((width, height)) if opts.split_spreads && is_landscape => {
+            debug!("{:?} is a spread ({}x{}), splitting it", item, width, height);
+            let left = format!("{}x{}+0+0", width / 2, height);
+            let right = format!("{}x{}+{}+0", width - width / 2, height, width / 2);
+            let (first, second) = if right_half_first(opts) { (right, left) } else { (left, right) };
+            vec![("-1", Some(first)), ("-2", Some(second))]
+        }
+        _ => vec![("", None)],
+    };
+    let rotate = if opts.split_spreads && is_landscape {
+        None
+    } else if opts.auto_rotate_landscape {
+        if is_landscape {
+            debug!("{:?} is landscape, rotating it", item);
+            Some(opts.rotate.clone().unwrap_or_else(|| "90".to_string()))
+        } else {
+            None
+        }
+    } else {
+        opts.rotate.clone()
+    };
+
+    let stem = result.file_stem().map_or_else(String::new, |stem| stem.to_string_lossy().to_string());
+    let started = Instant::now();
+    let mut produced = vec![];
+    for (suffix, crop) in &pieces {
+        let resize = match (opts.resize_threshold, dimensions) {
+            (Some(threshold), Some((width, height))) => {
+                // a split spread piece is half as wide
+                let width = if crop.is_some() { width / 2 } else { width };
+                let resize = width.max(height) > threshold;
+                debug!("{:?}{} is {}x{}, {} --resize-threshold {}", item, suffix, width, height,
+                    if resize { "resizing it, over" } else { "keeping its size, within" }, threshold);
+                resize
+            }
+            _ => true,
+        };
+        if let (Some(crop_to), Some((width, height))) = (&opts.crop_to, dimensions) {
+            let width = if crop.is_some() { width / 2 } else { width };
+            let turned = rotate.as_deref().is_some_and(|rotate| rotate == "90" || rotate == "270");
+            let size = if turned { (height, width) } else { (width, height) };
+            let size = if resize { resized_size(size, &geometry(opts)).unwrap_or(size) } else { size };
+            if let Some((Ok(crop_width), Ok(crop_height))) = crop_to.split_once('x').map(|(w, h)| (w.parse::<u32>(), h.parse::<u32>())) {
+                if size.0 < crop_width || size.1 < crop_height {
+                    warn!("{:?}{} is {}x{} once resized, smaller than --crop-to {}: it won't have the same size as the others", item, suffix, size.0, size.1, crop_to);
+                }
+            }
+        }
+        let outputs = if opts.sizes.is_empty() {
+            // --crop-to applies to pages kept at their size as well
+            let fit_crop = if resize || opts.crop_to.is_some() { fit_crop(opts) } else { None };
+            vec![(result.with_file_name(format!("{}{}.{}", stem, suffix, output_extension)), resize.then(|| geometry(opts)), fit_crop)]
+        } else {
+            // one output per width, e.g. `page@320.jpg`
+            opts.sizes.iter()
+                .map(|width| (result.with_file_name(format!("{}{}@{}.{}", stem, suffix, width, output_extension)), Some(format!("{}x", width)), None))
+                .collect()
+        };
+        for (result, geometry, fit_crop) in outputs {
+            let conversion = Conversion {
+                input: input.clone(),
+                result,
+                geometry,
+                quality: quality.clone(),
+                rotate: rotate.clone(),
+                crop: crop.clone(),
+                to_rgb,
+                fit_crop,
+                auto_orient,
+                debug_dir: debug_dir.clone(),
+            };
+            if let Some(target_ssim) = opts.target_ssim {
+                convert_for_target_ssim(item, &conversion, opts, target_ssim)?;
+            } else {
+                convert_image(item, &conversion, opts)?;
+            }
+            if let Some(metric) = measured_metric(opts) {
+                let value = measure(item, &conversion.result, metric, opts)?;
+                info!("{} of {:?}: {:.4}", metric, conversion.result, value);
+                if let Some(min_ssim) = opts.min_ssim {
+                    if value < min_ssim {
+                        warn!("{:?} has an SSIM of {:.4}, below --min-ssim {}: over-compressed?", conversion.result, value, min_ssim);
+                    }
+                }
+                stats.measurements.push((conversion.result.clone(), value));
+            }
+            stats.outputs += 1;
+            produced.push(conversion.result);
+        }
+    }
+    stats.reports.push(FileReport {
+        file: sub_path.to_path_buf(),
+        outputs: produced.iter().filter_map(|output| output.strip_prefix(target).ok()).map(Path::to_path_buf).collect(),
+        duration_ms: started.elapsed().as_millis(),
+        backend: "gm",
+    });
+    stats.conversions.push((item.to_path_buf(), produced.clone()));
+    stats.images += 1;
+    if !source_extension.eq_ignore_ascii_case(output_extension) {
+        stats.transcoded += 1;
+    }
+    Ok(produced)
+}
+
+/// Binary search of the quality range for the lowest quality reaching `target_ssim`,
+/// leaving the output encoded at that quality (or at the highest one tried if none does).
+fn convert_for_target_ssim(item: &Path, conversion: &Conversion, opts: &Opts, target_ssim: f64) -> Result<()> {
+    let (mut low, mut high) = (1u32, 100u32);
+    let mut best: Option<(u32, f64)> = None;
+    let mut last_encoded = None;
+    for _ in 0..opts.target_ssim_iterations {
+        if low > high {
+            break;
+        }
+        let quality = (low + high) / 2;
+        let trial = Conversion { quality: quality.to_string(), ..conversion.clone() };
+        convert_image(item, &trial, opts)?;
+        last_encoded = Some(quality);
+        let achieved = measure(item, &conversion.result, "ssim", opts)?;
+        debug!("{:?} at quality {}: SSIM {:.4}", conversion.result, quality, achieved);
+        if achieved >= target_ssim {
+            best = Some((quality, achieved));
+            high = quality - 1;
+        } else {
+            low = quality + 1;
+        }
+    }
+    let (quality, achieved) = match best {
+        Some(best) => best,
+        None => {
+            warn!("{:?} doesn't reach SSIM {} within {} tries, using quality 100",
+                conversion.result, target_ssim, opts.target_ssim_iterations);
+            let trial = Conversion { quality: "100".to_string(), ..conversion.clone() };
+            convert_image(item, &trial, opts)?;
+            return Ok(());
+        }
+    };
+    if last_encoded != Some(quality) {
+        let chosen = Conversion { quality: quality.to_string(), ..conversion.clone() };
+        convert_image(item, &chosen, opts)?;
+    }
+    info!("{:?}: quality {} for SSIM {:.4} (target {})", conversion.result, quality, achieved, target_ssim);
+    Ok(())
+}
+
+fn measured_metric(opts: &Opts) -> Option<&str> {
+    match (&opts.measure, opts.min_ssim) {
+        (Some(metric), _) => Some(metric.as_str()),
+        (None, Some(_)) => Some("ssim"),
+        (None, None) => None,
+    }
+}
+
+/// Compares `optimized` to `original` scaled to the same size, both as 8-bit grayscale.
+fn measure(original: &Path, optimized: &Path, metric: &str, opts: &Opts) -> Result<f64> {
+    let (width, height) = image_dimensions(optimized)?;
+    let reference = gray_pixels(original, width, height, opts)?;
+    let candidate = gray_pixels(optimized, width, height, opts)?;
+    if reference.len() != candidate.len() || reference.is_empty() {
+        bail!("couldn't get comparable pixels for {:?} and {:?}", original, optimized);
+    }
+    Ok(match metric {
+        "psnr" => psnr(&reference, &candidate),
+        _ => ssim(&reference, &candidate, width as usize, height as usize),
+    })
+}
+
+fn gray_pixels(image: &Path, width: u32, height: u32, opts: &Opts) -> Result<Vec<u8>> {
+    let mut command = gm_command(opts);
+    command.arg("convert").arg(image)
+        .arg("-resize").arg(format!("{}x{}!", width, height))
+        .args(["-colorspace", "Gray", "-depth", "8", "gray:-"]);
+    let output = run_gm(&mut command, opts.image_timeout)?;
+    if !output.status.success() {
+        bail!("`gm convert` invocation failed\n{}\n", String::from_utf8_lossy(&output.stderr));
+    }
+    Ok(output.stdout)
+}
+
+fn psnr(reference: &[u8], candidate: &[u8]) -> f64 {
+    let squared_error: f64 = reference.iter().zip(candidate)
+        .map(|(a, b)| (*a as f64 - *b as f64).powi(2))
+        .sum();
+    let mse = squared_error / reference.len() as f64;
+    if mse == 0.0 {
+        f64::INFINITY
+    } else {
+        10.0 * (255.0 * 255.0 / mse).log10()
+    }
+}
+
+/// Mean SSIM over 8x8 windows moved by 4 pixels.
+fn ssim(reference: &[u8], candidate: &[u8], width: usize, height: usize) -> f64 {
+    const WINDOW: usize = 8;
+    const STEP: usize = 4;
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+    if width < WINDOW || height < WINDOW {
+        return if reference == candidate { 1.0 } else { 0.0 };
+    }
+    let mut total = 0.0;
+    let mut windows = 0;
+    for top in (0..=height - WINDOW).step_by(STEP) {
+        for left in (0..=width - WINDOW).step_by(STEP) {
+            let (mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) = (0.0, 0.0, 0.0, 0.0, 0.0);
+            for y in top..top + WINDOW {
+                for x in left..left + WINDOW {
+                    let a = reference[y * width + x] as f64;
+                    let b = candidate[y * width + x] as f64;
+                    sum_a += a;
+                    sum_b += b;
+                    sum_aa += a * a;
+                    sum_bb += b * b;
+                    sum_ab += a * b;
+                }
+            }
+            let n = (WINDOW * WINDOW) as f64;
+            let (mean_a, mean_b) = (sum_a / n, sum_b / n);
+            let variance_a = sum_aa / n - mean_a * mean_a;
+            let variance_b = sum_bb / n - mean_b * mean_b;
+            let covariance = sum_ab / n - mean_a * mean_b;
+            total += ((2.0 * mean_a * mean_b + C1) * (2.0 * covariance + C2))
+                / ((mean_a * mean_a + mean_b * mean_b + C1) * (variance_a + variance_b + C2));
+            windows += 1;
+        }
+    }
+    total / windows as f64
+}
+
+fn right_half_first(opts: &Opts) -> bool {
+    match opts.split_order.as_deref() {
+        Some(order) => order == "right-first",
+        None => opts.reading_direction.as_deref() == Some("rtl"),
+    }
+}
+
+fn gm_convert_args(conversion: &Conversion, opts: &Opts) -> Vec<String> {
+    let result = &conversion.result;
+    let mut args: Vec<String> = vec![
+        "convert".to_string(), conversion.input.clone(),
+    ];
+    if conversion.auto_orient {
+        // upright first, so that crop and trim work on the page as it is displayed
+        args.push("-auto-orient".to_string());
+    }
+    if conversion.to_rgb {
+        // drop the CMYK ICC profile too, it would contradict the converted pixels
+        args.extend(vec!["-colorspace".to_string(), "RGB".to_string(), "+profile".to_string(), "icc".to_string()]);
+    }
+    if let Some(crop) = &conversion.crop {
+        args.extend(vec!["-crop".to_string(), crop.clone(), "+repage".to_string()]);
+    }
+    if opts.trim {
+        // trim first so the geometry applies to the cropped page
+        if let Some(fuzz) = opts.trim_fuzz {
+            args.push("-fuzz".to_string());
+            args.push(format!("{}%", fuzz));
+        }
+        args.extend(vec!["-trim".to_string(), "+repage".to_string()]);
+    }
+    if let Some(degrees) = &conversion.rotate {
+        args.push("-rotate".to_string());
+        args.push(degrees.to_string());
+    }
+    // contrast on the full-size page, the resize then averages the corrected pixels
+    if opts.normalize_contrast {
+        args.push("-normalize".to_string());
+    }
+    if let Some((black, white)) = opts.contrast_stretch.as_deref().and_then(|spec| spec.split_once(['x', 'X'])) {
+        let (black, white) = (black.trim_end_matches('%'), white.trim_end_matches('%'));
+        let white_point = 100.0 - white.parse::<f64>().unwrap_or_default();
+        args.extend(vec!["-level".to_string(), format!("{}%,1.0,{}%", black, white_point)]);
+    }
+    if let Some(filter) = &opts.resize_filter {
+        args.push("-filter".to_string());
+        args.push(filter.to_string());
+    }
+    if let Some(geometry) = &conversion.geometry {
+        let resize_operator = if opts.max_width.is_some() || opts.max_height.is_some() { "-resize" } else { "-geometry" };
+        args.extend(vec![resize_operator.to_string(), geometry.clone()]);
+    }
+    if let Some(crop) = &conversion.fit_crop {
+        let gravity = opts.crop_gravity.as_deref().unwrap_or("center");
+        args.extend(vec!["-gravity".to_string(), gravity.to_string(), "-crop".to_string(), crop.clone(), "+repage".to_string()]);
+    }
+    args.extend(vec!["-quality".to_string(), conversion.quality.clone()]);
+
+    let is_paletted = opts.colors.is_some()
+        || result.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("gif"));
+    if is_paletted && opts.dither {
+        args.push("-dither".to_string());
+    } else if is_paletted && opts.no_dither {
+        args.push("+dither".to_string());
+    }
+    if let Some(colors) = opts.colors {
+        args.push("-colors".to_string());
+        args.push(colors.to_string());
+    }
+    if let Some(define) = &opts.define {
+        args.push("-define".to_string());
+        args.push(define.to_string());
+    }
+    if let Some(dpi) = opts.set_dpi {
+        args.extend(vec!["-units".to_string(), "PixelsPerInch".to_string(), "-density".to_string(), format!("{}x{}", dpi, dpi)]);
+    } else if opts.strip_dpi {
+        // JFIF and PNG pHYs then only store an aspect ratio
+        args.extend(vec!["-units".to_string(), "Undefined".to_string()]);
+    }
+    if let Some(depth) = opts.bit_depth {
+        args.extend(vec!["-depth".to_string(), depth.to_string()]);
+    }
+    if let Some(subsampling) = &opts.subsampling {
+        args.extend(subsampling_args(subsampling, result.extension()));
+    }
+    args.extend(effort_args(opts.effort, result.extension()));
+    if result.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("avif")) {
+        if let Some(encoder) = &opts.avif_encoder {
+            args.extend(vec!["-define".to_string(), format!("avif:encoder={}", encoder)]);
+        }
+        if let Some(threads) = opts.avif_threads {
+            args.extend(vec!["-define".to_string(), format!("avif:threads={}", threads)]);
+        }
+    }
+    args.extend(opts.gm_arg.iter().cloned());
+    args.push(result.to_str().unwrap().to_string());
+    args
+}
+
+/// A `gm` command with the --gm-env variables set.
+fn gm_command(opts: &Opts) -> Command {
+    let mut command = Command::new("gm");
+    for (key, value) in opts.gm_env.iter().filter_map(|variable| variable.split_once('=')) {
+        command.env(key, value);
+    }
+    command
+}
+
+/// Runs a `gm` command in one of the --max-gm-processes slots, killed after `timeout` seconds.
+fn run_gm(command: &mut Command, timeout: Option<u64>) -> Result<Output> {
+    debug!("Command: {:?}", command);
+    let _slot = GM_PROCESSES.acquire();
+    match run_with_timeout(command, timeout.map(Duration::from_secs))? {
+        Some(output) => Ok(output),
+        None => {
+            let subcommand = command.get_args().next().unwrap_or_default().to_string_lossy().to_string();
+            bail!("`gm {}` timed out after {}s", subcommand, timeout.unwrap_or_default())
+        }
+    }
+}
+
+fn convert_image(item: &Path, conversion: &Conversion, opts: &Opts) -> Result<()> {
+    let mut command = gm_command(opts);
+    command.args(gm_convert_args(conversion, opts));
+    debug!("Command: {:?}", command);
+    if conversion.debug_dir.is_some() {
+        info!("--debug-image {:?}: {:?}", item, command);
+    }
+    // a leftover from a previous encode (--target-ssim) would hide a run that wrote nothing
+    if conversion.result.exists() {
+        std::fs::remove_file(&conversion.result)?;
+    }
+
+    let output = {
+        let _slot = GM_PROCESSES.acquire();
+        let _span = TRACER.span("encode", "gm convert", &item.to_string_lossy());
+        run_with_timeout(&mut command, opts.image_timeout.map(Duration::from_secs))?
+    };
+    if let Some(debug_dir) = &conversion.debug_dir {
+        keep_debug_output(debug_dir, &command, output.as_ref(), &conversion.result)?;
+    }
+    let checked = check_conversion(item, conversion, opts, output);
+    // a partial output left by a failed run would be packed and counted as a written page
+    if checked.is_err() && conversion.result.exists() {
+        std::fs::remove_file(&conversion.result)?;
+    }
+    checked
+}
+
+/// Checks the outcome of a `gm convert` run, `None` when it timed out, and post-processes its output.
+fn check_conversion(item: &Path, conversion: &Conversion, opts: &Opts, output: Option<Output>) -> Result<()> {
+    let output = match output {
+        Some(output) => output,
+        None => {
+            warn!("`gm convert` timed out on {:?}", item);
+            bail!("`gm convert` timed out after {}s on {:?}", opts.image_timeout.unwrap_or_default(), item);
+        }
+    };
+    if output.status.success() {
+        if !output.stdout.is_empty() {
+            debug!("`gm convert` wrote {} bytes to stdout for {:?}", output.stdout.len(), item);
+        }
+        if !output.stderr.is_empty() {
+            debug!("`gm convert` stderr for {:?}: {}", item, String::from_utf8_lossy(&output.stderr).trim_end());
+        }
+        if !conversion.result.is_file() {
+            bail!("`gm convert` succeeded on {:?} but didn't create {:?}", item, conversion.result);
+        }
+        if opts.png_lossy && has_extension(&conversion.result, "png") {
+            quantize_png(&conversion.result, &opts.png_quality)?;
+        }
+        if opts.strip_gps {
+            strip_gps(&conversion.result)?;
+        }
+        Ok(())
+    } else {
+        let error = format!("`gm convert` invocation failed\n{}\n",
+        String::from_utf8_lossy(&output.stderr));
+        Err(anyhow!(error))
+    }
+}
+
+/// Copies a --debug-image source to a temp dir of its own, kept after the run, and returns it.
+fn keep_debug_source(item: &Path, sub_path: &Path) -> Result<PathBuf> {
+    let debug_dir = tempfile::Builder::new().prefix("img-optim-debug").tempdir()?.into_path();
+    // `source-` keeps it apart from an output of the same name
+    std::fs::copy(item, debug_dir.join(format!("source-{}", item.file_name().unwrap_or_default().to_string_lossy())))?;
+    info!("--debug-image {:?}: intermediate files kept in {:?}", sub_path, debug_dir);
+    Ok(debug_dir)
+}
+
+/// Writes `<output>.command.txt`, `<output>.stderr.txt` and a copy of the output, if any, to the
+/// --debug-image dir; a retried conversion (--target-ssim) replaces the previous attempt.
+fn keep_debug_output(debug_dir: &Path, command: &Command, output: Option<&std::process::Output>, result: &Path) -> Result<()> {
+    let name = result.file_name().unwrap_or_default().to_string_lossy().to_string();
+    std::fs::write(debug_dir.join(format!("{}.command.txt", name)), format!("{:?}\n", command))?;
+    let stderr = match output {
+        Some(output) => format!("{}{}\n", String::from_utf8_lossy(&output.stderr), output.status),
+        None => "timed out\n".to_string(),
+    };
+    std::fs::write(debug_dir.join(format!("{}.stderr.txt", name)), stderr)?;
+    if result.is_file() {
+        std::fs::copy(result, debug_dir.join(&name))?;
+    }
+    Ok(())
+}
+
+/// Replaces `png` with its pngquant palette version, unless pngquant can't reach the quality range.
+fn quantize_png(png: &Path, quality: &str) -> Result<()> {
+    let mut command = Command::new("pngquant");
+    command.arg("--quality").arg(quality).arg("--force").arg("--skip-if-larger")
+        .arg("--output").arg(png).arg(png);
+    debug!("Command: {:?}", command);
+    let output = command.output()?;
+    match output.status.code() {
+        Some(0) => Ok(()),
+        // 99: below the minimum quality, 98: the result would be larger
+        Some(98) | Some(99) => {
+            debug!("pngquant kept {:?} lossless ({})", png, if output.status.code() == Some(99) { "quality too low" } else { "no gain" });
+            Ok(())
+        }
+        _ => bail!("`pngquant` failed on {:?}\n{}\n", png, String::from_utf8_lossy(&output.stderr)),
+    }
+}
+
+/// Rewrites the EXIF block of `image` without its GPS IFD, leaving everything else as produced by gm.
+fn strip_gps(image: &Path) -> Result<()> {
+    use exif::Context;
+    use img_parts::{Bytes, DynImage, ImageEXIF};
+
+    let parsed = match DynImage::from_bytes(Bytes::from(std::fs::read(image)?))? {
+        Some(parsed) => parsed,
+        None => {
+            debug!("{:?} has no EXIF-capable container, not stripping GPS", image);
+            return Ok(());
+        }
+    };
+    let raw_exif = match parsed.exif() {
+        Some(raw_exif) => raw_exif,
+        None => return Ok(()),
+    };
+    let exif = exif::Reader::new().read_raw(raw_exif.to_vec())?;
+    if !exif.fields().any(|field| field.tag.context() == Context::Gps) {
+        return Ok(());
+    }
+
+    let fields: Vec<_> = exif.fields().filter(|field| field.tag.context() != Context::Gps).cloned().collect();
+    rewrite_exif(image, parsed, &exif, &fields)?;
+    debug!("stripped GPS data from {:?}", image);
+    Ok(())
+}
+
+/// Sets the EXIF orientation of `image` back to normal (1), once its pixels have been turned.
+fn reset_orientation(image: &Path) -> Result<()> {
+    use exif::{In, Tag, Value};
+    use img_parts::{Bytes, DynImage, ImageEXIF};
+
+    let Some(parsed) = DynImage::from_bytes(Bytes::from(std::fs::read(image)?))? else { return Ok(()) };
+    let Some(raw_exif) = parsed.exif() else { return Ok(()) };
+    let exif = exif::Reader::new().read_raw(raw_exif.to_vec())?;
+    let fields: Vec<_> = exif.fields().cloned()
+        .map(|mut field| {
+            if field.tag == Tag::Orientation && field.ifd_num == In::PRIMARY {
+                field.value = Value::Short(vec![1]);
+            }
+            field
+        })
+        .collect();
+    rewrite_exif(image, parsed, &exif, &fields)
+}
+
+/// Replaces the EXIF block of `image` (already parsed as `parsed`, with `exif` its current EXIF data)
+/// by one holding `fields`, keeping the thumbnail.
+fn rewrite_exif(image: &Path, mut parsed: img_parts::DynImage, exif: &exif::Exif, fields: &[exif::Field]) -> Result<()> {
+    use exif::{In, Tag};
+    use img_parts::{Bytes, ImageEXIF};
+
+    let mut writer = exif::experimental::Writer::new();
+    for field in fields {
+        writer.push_field(field);
+    }
+    let thumbnail_offset = exif.get_field(Tag::JPEGInterchangeFormat, In::THUMBNAIL)
+        .and_then(|field| field.value.get_uint(0));
+    let thumbnail_length = exif.get_field(Tag::JPEGInterchangeFormatLength, In::THUMBNAIL)
+        .and_then(|field| field.value.get_uint(0));
+    if let (Some(offset), Some(length)) = (thumbnail_offset, thumbnail_length) {
+        if let Some(thumbnail) = exif.buf().get(offset as usize..(offset + length) as usize) {
+            writer.set_jpeg(thumbnail, In::THUMBNAIL);
+        }
+    }
+    let mut stripped = std::io::Cursor::new(vec![]);
+    writer.write(&mut stripped, exif.little_endian())?;
+
+    parsed.set_exif(Some(Bytes::from(stripped.into_inner())));
+    parsed.encoder().write_to(std::fs::File::create(image)?)?;
+    Ok(())
+}
+
+/// Runs `command` to completion, or kills it and returns `None` once `timeout` has elapsed.
+fn run_with_timeout(command: &mut Command, timeout: Option<Duration>) -> Result<Option<Output>> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return Ok(Some(command.output()?)),
+    };
+    let mut child = command.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+    // drain the pipes while waiting so a chatty process can't block on a full buffer
+    let stdout = child.stdout.take().map(drain_in_background);
+    let stderr = child.stderr.take().map(drain_in_background);
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            child.kill()?;
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+    let collect = |reader: Option<thread::JoinHandle<Vec<u8>>>| {
+        reader.map(|handle| handle.join().unwrap_or_default()).unwrap_or_default()
+    };
+    Ok(Some(Output { status, stdout: collect(stdout), stderr: collect(stderr) }))
+}
+
+fn drain_in_background<R: Read + Send + 'static>(mut reader: R) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buffer = vec![];
+        let _ = reader.read_to_end(&mut buffer);
+        buffer
+    })
+}
+
+fn subsampling_args(subsampling: &str, extension: Option<&OsStr>) -> Vec<String> {
+    let is_webp = extension.is_some_and(|ext| ext.eq_ignore_ascii_case("webp"));
+    if is_webp {
+        match subsampling {
+            "444" => vec!["-define".to_string(), "webp:use-sharp-yuv=true".to_string()],
+            _ => vec![],
+        }
+    } else {
+        let factor = match subsampling {
+            "444" => "1x1",
+            "422" => "2x1",
+            _ => "2x2",
+        };
+        vec!["-sampling-factor".to_string(), factor.to_string()]
+    }
+}
+
+/// Translates --effort into the format's own knob: WebP `method` 0-6 (the default 5 gives
+/// libwebp's usual 4), AVIF `speed` 10-0 (inverted, 5 stays 5). Other formats have none.
+fn effort_args(effort: u8, extension: Option<&OsStr>) -> Vec<String> {
+    const WEBP_METHODS: [u8; 11] = [0, 1, 1, 2, 3, 4, 4, 5, 5, 6, 6];
+    let extension = extension.map_or_else(String::new, |ext| ext.to_string_lossy().to_lowercase());
+    match extension.as_str() {
+        "webp" => vec!["-define".to_string(), format!("webp:method={}", WEBP_METHODS[effort as usize])],
+        "avif" => vec!["-define".to_string(), format!("avif:speed={}", 10 - effort)],
+        _ => vec![],
+    }
+}
+
+fn repack_output(dir: &tempfile::TempDir, zip: &Path, opts: &Opts, metadata: &ArchiveMetadata) -> Result<()> {
+    if is_tar_zst(zip) {
+        if !metadata.comment.is_empty() {
+            warn!("tar has no archive comment, dropping it");
+        }
+        write_tar_zst(dir.path(), zip, opts)
+    } else if opts.streamable {
+        if opts.compression.as_deref() == Some("zstd") {
+            bail!("--streamable only writes deflate entries, it can't be combined with --compression zstd");
+        }
+        write_streamable_zip(dir.path(), zip, opts, metadata)
+    } else if opts.compression.as_deref() == Some("zstd") {
+        write_native_zip(dir.path(), zip, zstd_method()?, opts, metadata)
+    } else if opts.deterministic || opts.smart_compression || !metadata.comment.is_empty() {
+        // the `zip` binary stores file timestamps and needs the comment on stdin,
+        // the native writer handles both directly, and per-entry methods
+        write_native_zip(dir.path(), zip, zip::CompressionMethod::Deflated, opts, metadata)
+    } else {
+        run_zip(dir, zip)
+    }
+}
+
+/// `book.cbz` becomes `book.part2.cbz`, `book.tar.zst` becomes `book.part2.tar.zst`.
+fn part_path(target: &Path, index: usize) -> PathBuf {
+    let name = target.file_name().map_or_else(String::new, |name| name.to_string_lossy().to_string());
+    let split_at = if is_tar_zst(target) { name.len() - ".tar.zst".len() } else { name.rfind('.').unwrap_or(name.len()) };
+    let (stem, extension) = name.split_at(split_at);
+    target.with_file_name(format!("{}.part{}{}", stem, index, extension))
+}
+
+/// Groups the processed files, in page order, into runs whose total size stays under `split_size`
+/// (a file bigger than that gets a part of its own), and packs each run as its own archive.
+/// Returns every part with the directory it was packed from.
+fn write_parts(processed_dir: &Path, target: &Path, split_size: u64, opts: &Opts, metadata: &ArchiveMetadata, stats: &ArchiveStats)
+    -> Result<Vec<(PathBuf, tempfile::TempDir)>> {
+    // room for the local and central headers, which both repeat the entry name
+    let packed_size = |sub_path: &Path, size: u64| size + 100 + 2 * entry_name(sub_path).len() as u64;
+    let mut groups: Vec<Vec<PathBuf>> = vec![];
+    let mut group_size = 0;
+    for sub_path in list_entries(processed_dir)? {
+        let full_path = processed_dir.join(&sub_path);
+        if !full_path.is_file() {
+            continue;
+        }
+        let size = packed_size(&sub_path, full_path.metadata()?.len());
+        if size > split_size {
+            warn!("{:?} alone is bigger than --split-size {}", sub_path, split_size);
+        }
+        match groups.last_mut() {
+            Some(group) if group_size + size <= split_size => {
+                group.push(sub_path);
+                group_size += size;
+            }
+            _ => {
+                groups.push(vec![sub_path]);
+                group_size = size;
+            }
+        }
+    }
+
+    let mut parts = vec![];
+    let mut packed_files = 0;
+    for (index, group) in groups.iter().enumerate() {
+        let part_dir = tempfile::Builder::new().prefix("img-optim-part").tempdir()?;
+        for sub_path in group {
+            let destination = part_dir.path().join(sub_path);
+            create_parent(&destination)?;
+            if std::fs::hard_link(processed_dir.join(sub_path), &destination).is_err() {
+                std::fs::copy(processed_dir.join(sub_path), &destination)?;
+            }
+        }
+        let part = part_path(target, index + 1);
+        write_atomically(&part, |partial| {
+            repack_output(&part_dir, partial, opts, metadata)?;
+            if !is_tar_zst(&part) {
+                packed_files += count_zip_files(partial)?;
+            }
+            Ok(())
+        })?;
+        let size = part.metadata()?.len();
+        if size > split_size {
+            warn!("{:?} is {} bytes, over --split-size {}", part, size, split_size);
+        }
+        info!("{:?}: {} files, {} bytes", part, group.len(), size);
+        parts.push((part, part_dir));
+    }
+    if !is_tar_zst(target) {
+        check_page_count(stats, packed_files, "the parts")?;
+    }
+    Ok(parts)
+}
+
+/// Montage of the processed pages, in archive order, for --contact-sheet.
+fn write_contact_sheet(processed_dir: &Path, sheet: &Path, opts: &Opts) -> Result<()> {
+    let pages: Vec<PathBuf> = list_entries(processed_dir)?.into_iter()
+        .filter(|sub_path| is_image(sub_path) && processed_dir.join(sub_path).is_file())
+        .collect();
+    if pages.is_empty() {
+        info!("no page in {}, no contact sheet", opts.source);
+        return Ok(());
+    }
+    create_parent(sheet)?;
+    let size = opts.contact_sheet_size;
+    let mut command = gm_command(opts);
+    command.arg("montage")
+        .arg("-tile").arg(format!("{}x", opts.contact_sheet_columns.max(1)))
+        .arg("-geometry").arg(format!("{}x{}+4+4", size, size))
+        .args(&pages)
+        .arg(sheet.absolutize()?.as_ref())
+        .current_dir(processed_dir);
+    let output = run_gm(&mut command, None)?;
+    if !output.status.success() {
+        bail!("`gm montage` invocation failed\n{}\n", String::from_utf8_lossy(&output.stderr));
+    }
+    info!("contact sheet of {} pages written to {:?}", pages.len(), sheet);
+    Ok(())
+}
+
+/// The --marker entry: `{"tool": "img-optim", "version": "...", "date": "...", "options": "<image options hash>", "settings": {...}}`.
+fn write_marker(dir: &Path, opts: &Opts, page_options: &HashMap<String, PageOptions>) -> Result<()> {
+    let marker = serde_json::json!({
+        "tool": "img-optim",
+        "version": env!("CARGO_PKG_VERSION"),
+        "date": humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        "options": image_options_hash(opts, page_options)?,
+        "settings": {
+            "geometry": geometry(opts), "quality": opts.quality.clone().unwrap_or_else(|| default_quality(opts)),
+            "extension": output_extension(opts),
+        },
+    });
+    std::fs::write(dir.join(MARKER_NAME), serde_json::to_string_pretty(&marker)?)?;
+    Ok(())
+}
+
+/// Descriptions (`version X, date`) of the markers found at the root of an unpacked source,
+/// or of each merged source with `merged`.
+fn find_markers(unpack_dir: &Path, merged: bool) -> Result<Vec<String>> {
+    let mut roots = vec![unpack_dir.to_path_buf()];
+    if merged {
+        for entry in std::fs::read_dir(unpack_dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                roots.push(entry.path());
+            }
+        }
+    }
+    let mut markers = vec![];
+    for root in roots {
+        let path = root.join(MARKER_NAME);
+        if !path.is_file() {
+            continue;
+        }
+        let marker: serde_json::Value = serde_json::from_slice(&std::fs::read(&path)?).unwrap_or_default();
+        markers.push(format!("version {}, {}",
+            marker["version"].as_str().unwrap_or("unknown"), marker["date"].as_str().unwrap_or("unknown date")));
+    }
+    Ok(markers)
+}
+
+fn is_tar_zst(path: &Path) -> bool {
+    path.to_str().is_some_and(|name| name.to_lowercase().ends_with(".tar.zst"))
+}
+
+/// Compares what processing reported writing with the files actually found in `what`,
+/// so that a page lost between the two (skipped, overwritten by a same-named output,
+/// dropped while packing) fails the archive instead of going unnoticed.
+fn check_page_count(stats: &ArchiveStats, found: usize, what: &str) -> Result<()> {
+    let expected = stats.outputs + stats.copied + stats.nested + stats.own_files;
+    if found != expected {
+        bail!("{} has {} files but {} were written ({:+}), pages were lost", what, found, expected, found as i64 - expected as i64);
+    }
+    Ok(())
+}
+
+fn count_zip_files(zip_path: &Path) -> Result<usize> {
+    let mut archive = zip::ZipArchive::new(std::fs::File::open(zip_path)?)?;
+    let mut count = 0;
+    for index in 0..archive.len() {
+        if !archive.by_index(index)?.is_dir() {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+fn run_zip(dir: &tempfile::TempDir, zip: &Path) -> Result<()> {
+    // `zip` adds to an existing archive, which would keep stale entries from a previous run
+    remove_existing(zip)?;
+    let zip_path= zip.to_str().unwrap();
+    let mut command = Command::new("zip");
+    command.args(vec![
+        "--recurse-paths",
+        zip_path, // zip file
+        "." // what to add
+    ]);
+    command.current_dir(dir);
+
+    let output = command.output()?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        let error = format!("`zip` invocation failed\n{}\n",
+                                    String::from_utf8_lossy(&output.stderr));
+        Err(anyhow!(error))
+    }
+}
+
+/// Relative paths of everything under `dir`, directories before their content, sorted by name.
+fn list_entries(dir: &Path) -> Result<Vec<PathBuf>> {
+    let walker = globwalk::GlobWalkerBuilder::from_patterns(dir, &[ "**/*" ])
+        .file_type(GlobFileType::FILE | GlobFileType::DIR)
+        .contents_first(false)
+        .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+        .build()?
+        .filter_map(Result::ok);
+    let mut result = vec![];
+    for entry in walker {
+        result.push(entry.path().strip_prefix(dir)?.to_path_buf());
+    }
+    Ok(result)
+}
+
+/// Name of an entry inside an archive, always using `/` as separator.
+fn entry_name(sub_path: &Path) -> String {
+    sub_path.components()
+        .map(|part| part.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn write_native_zip(dir: &Path, zip: &Path, method: zip::CompressionMethod, opts: &Opts, metadata: &ArchiveMetadata) -> Result<()> {
+    use zip::write::SimpleFileOptions;
+
+    let mut options = SimpleFileOptions::default().compression_method(method);
+    if opts.deterministic {
+        options = options.last_modified_time(zip::DateTime::default());
+    }
+    let mut writer = zip::ZipWriter::new(std::fs::File::create(zip)?);
+    writer.set_raw_comment(metadata.comment.clone().into_boxed_slice());
+    for sub_path in list_entries(dir)? {
+        let full_path = dir.join(&sub_path);
+        let name = entry_name(&sub_path);
+        if full_path.is_dir() {
+            debug!("add directory {}", name);
+            writer.add_directory(name, options)?;
+        } else {
+            // recompressing a JPEG or PNG costs time for a few bytes at best
+            let store = opts.smart_compression && is_image(&full_path);
+            let entry_options = if store { options.compression_method(zip::CompressionMethod::Stored) } else { options };
+            debug!("add file {} ({})", name, if store { "stored" } else { "compressed" });
+            writer.start_file(name, entry_options)?;
+            std::io::copy(&mut std::fs::File::open(full_path)?, &mut writer)?;
+        }
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+/// The --streamable writer: each file is deflated behind a local header with general purpose bit 3
+/// set and zero sizes, its CRC and sizes following the data in a data descriptor, the way a writer
+/// that can't seek back produces it. Directories and --smart-compression stored entries keep their
+/// sizes in the local header: a stored entry with a descriptor gives a streaming reader no way to
+/// find the end of its data. No zip64, so at most 65535 entries and 4 GiB.
+fn write_streamable_zip(dir: &Path, zip: &Path, opts: &Opts, metadata: &ArchiveMetadata) -> Result<()> {
+    use std::convert::TryFrom;
+
+    const DEFLATED: u16 = 8;
+    const DATA_DESCRIPTOR: u16 = 1 << 3;
+    const UTF8_NAME: u16 = 1 << 11;
+    let time = if opts.deterministic { zip::DateTime::default() } else { zip::DateTime::default_for_write() };
+    let too_big = || anyhow!("{:?} would need zip64, which --streamable doesn't write", zip);
+
+    let mut out = std::io::BufWriter::new(std::fs::File::create(zip)?);
+    let mut offset: u64 = 0;
+    let mut central = vec![];
+    let mut entries: u16 = 0;
+    for sub_path in list_entries(dir)? {
+        let full_path = dir.join(&sub_path);
+        let is_dir = full_path.is_dir();
+        let name = if is_dir { format!("{}/", entry_name(&sub_path)) } else { entry_name(&sub_path) };
+        let data = if is_dir { vec![] } else { std::fs::read(&full_path)? };
+        let deflate = !(is_dir || opts.smart_compression && is_image(&full_path));
+        let crc = crc32fast::hash(&data);
+        let packed = if deflate {
+            let mut encoder = flate2::write::DeflateEncoder::new(vec![], flate2::Compression::default());
+            encoder.write_all(&data)?;
+            encoder.finish()?
+        } else {
+            data.clone()
+        };
+        let (size, packed_size) = (u32::try_from(data.len()).map_err(|_| too_big())?, u32::try_from(packed.len()).map_err(|_| too_big())?);
+        let flags = if deflate { DATA_DESCRIPTOR } else { 0 } | if name.is_ascii() { 0 } else { UTF8_NAME };
+        let method = if deflate { DEFLATED } else { 0 };
+        debug!("add {} ({})", name, if deflate { "deflated, with data descriptor" } else { "stored" });
+
+        let mut local = vec![];
+        local.extend(0x04034b50u32.to_le_bytes());
+        local.extend(20u16.to_le_bytes());
+        local.extend(flags.to_le_bytes());
+        local.extend(method.to_le_bytes());
+        local.extend(time.timepart().to_le_bytes());
+        local.extend(time.datepart().to_le_bytes());
+        // known up front for stored entries only, the others have them in the descriptor
+        for value in if deflate { [0, 0, 0] } else { [crc, packed_size, size] } {
+            local.extend(value.to_le_bytes());
+        }
+        local.extend((name.len() as u16).to_le_bytes());
+        local.extend(0u16.to_le_bytes());
+        local.extend(name.as_bytes());
+        local.extend(&packed);
+        if deflate {
+            local.extend(0x08074b50u32.to_le_bytes());
+            for value in [crc, packed_size, size] {
+                local.extend(value.to_le_bytes());
+            }
+        }
+        out.write_all(&local)?;
+
+        let mode: u32 = if is_dir { 0o40755 } else { 0o100644 };
+        central.extend(0x02014b50u32.to_le_bytes());
+        central.extend(0x031eu16.to_le_bytes()); // made by unix, spec 3.0
+        central.extend(20u16.to_le_bytes());
+        central.extend(flags.to_le_bytes());
+        central.extend(method.to_le_bytes());
+        central.extend(time.timepart().to_le_bytes());
+        central.extend(time.datepart().to_le_bytes());
+        for value in [crc, packed_size, size] {
+            central.extend(value.to_le_bytes());
+        }
+        central.extend((name.len() as u16).to_le_bytes());
+        central.extend([0u8; 8]); // extra and comment lengths, disk, internal attributes
+        central.extend((mode << 16 | if is_dir { 0x10 } else { 0 }).to_le_bytes());
+        central.extend(u32::try_from(offset).map_err(|_| too_big())?.to_le_bytes());
+        central.extend(name.as_bytes());
+
+        offset += local.len() as u64;
+        entries = entries.checked_add(1).ok_or_else(too_big)?;
+    }
+    out.write_all(&central)?;
+    let mut end = vec![];
+    end.extend(0x06054b50u32.to_le_bytes());
+    end.extend([0u8; 4]); // disk numbers
+    end.extend(entries.to_le_bytes());
+    end.extend(entries.to_le_bytes());
+    end.extend(u32::try_from(central.len()).map_err(|_| too_big())?.to_le_bytes());
+    end.extend(u32::try_from(offset).map_err(|_| too_big())?.to_le_bytes());
+    end.extend(u16::try_from(metadata.comment.len()).map_err(|_| anyhow!("archive comment too long for a zip"))?.to_le_bytes());
+    end.extend(&metadata.comment);
+    out.write_all(&end)?;
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_method() -> Result<zip::CompressionMethod> {
+    Ok(zip::CompressionMethod::Zstd)
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_method() -> Result<zip::CompressionMethod> {
+    bail!("zstd compression requires img-optim to be built with the `zstd` feature")
+}
+
+#[cfg(feature = "zstd")]
+fn write_tar_zst(dir: &Path, target: &Path, opts: &Opts) -> Result<()> {
+    let encoder = zstd::Encoder::new(std::fs::File::create(target)?, 0)?;
+    let mut builder = tar::Builder::new(encoder);
+    if opts.deterministic {
+        builder.mode(tar::HeaderMode::Deterministic);
+    }
+    for sub_path in list_entries(dir)? {
+        debug!("add {:?}", sub_path);
+        builder.append_path_with_name(dir.join(&sub_path), &sub_path)?;
+    }
+    builder.into_inner()?.finish()?;
+    Ok(())
+}
+
+#[cfg(not(feature = "zstd"))]
+fn write_tar_zst(_dir: &Path, _target: &Path, _opts: &Opts) -> Result<()> {
+    bail!(".tar.zst output requires img-optim to be built with the `zstd` feature")
+}
+
+/// Replaces files with the same content as an earlier file by a hardlink to it,
+/// keeping the copy where the filesystem doesn't support links.
+fn hardlink_duplicates(dir: &Path) -> Result<()> {
+    let mut first_by_hash: HashMap<(u64, String), PathBuf> = HashMap::new();
+    let mut saved: u64 = 0;
+    for sub_path in list_entries(dir)? {
+        let path = dir.join(&sub_path);
+        if !path.is_file() {
+            continue;
+        }
+        let size = std::fs::metadata(&path)?.len();
+        let key = (size, sha256_file(&path)?);
+        match first_by_hash.get(&key) {
+            Some(original) => {
+                let link = path.with_extension("img-optim-link");
+                match std::fs::hard_link(original, &link) {
+                    Ok(()) => {
+                        std::fs::rename(&link, &path)?;
+                        debug!("{:?} is a duplicate of {:?}, hardlinked", path, original);
+                        saved += size;
+                    }
+                    Err(error) => {
+                        warn!("couldn't hardlink {:?} to {:?}, keeping the copy - {}", path, original, error);
+                    }
+                }
+            }
+            None => {
+                first_by_hash.insert(key, path);
+            }
+        }
+    }
+    info!("hardlinking duplicates saved {} bytes in {:?}", saved, dir);
+    Ok(())
+}
+
+/// Lets `write` produce `target` under a temporary name in the same directory, then renames
+/// it into place: an interrupted or failed write never replaces a previous good file.
+fn write_atomically<F>(target: &Path, write: F) -> Result<()>
+    where F: FnOnce(&Path) -> Result<()> {
+    let directory = target.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+    // keep the whole name as suffix, writers pick the output format from the extension
+    let suffix = format!(".{}", target.file_name().unwrap_or_default().to_string_lossy());
+    let partial = tempfile::Builder::new().prefix(".img-optim-").suffix(&suffix).tempfile_in(directory)?;
+    write(partial.path())?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        // temp files are private, give the result the permissions of the file it replaces
+        let permissions = match std::fs::metadata(target) {
+            Ok(existing) => existing.permissions(),
+            Err(_) => std::fs::Permissions::from_mode(0o644),
+        };
+        std::fs::set_permissions(partial.path(), permissions)?;
+    }
+    partial.persist(target)?;
+    Ok(())
+}
+
+fn write_manifest(manifest: &Path, archive: &Path, processed_dir: &Path, with_entries: bool) -> Result<()> {
+    let mut lines = format!("{}  {}\n", sha256_file(archive)?, archive.display());
+    if with_entries {
+        // entries are hashed from the processed tree, which is exactly what was packed
+        for sub_path in list_entries(processed_dir)? {
+            let full_path = processed_dir.join(&sub_path);
+            if full_path.is_file() {
+                lines.push_str(&format!("    {}  {}\n", sha256_file(&full_path)?, entry_name(&sub_path)));
+            }
+        }
+    }
+    let mut content = if manifest.exists() { std::fs::read_to_string(manifest)? } else { String::new() };
+    content.push_str(&lines);
+    write_atomically(manifest, |partial| Ok(std::fs::write(partial, &content)?))?;
+    info!("manifest {:?} updated for {:?}", manifest, archive);
+    Ok(())
+}
+
+/// Appends the `--report` records of one archive, each tagged with the archive it came from;
+/// the whole file is written again so that an interrupted run leaves the previous one whole.
+fn write_report(report: &Path, archive: &str, stats: &ArchiveStats) -> Result<()> {
+    let mut content = if report.exists() { std::fs::read_to_string(report)? } else { String::new() };
+    for record in &stats.reports {
+        let mut line = serde_json::to_value(record)?;
+        line["archive"] = serde_json::Value::from(archive);
+        content.push_str(&format!("{}\n", line));
+    }
+    write_atomically(report, |partial| Ok(std::fs::write(partial, &content)?))
+}
+
+/// The --embed-report entry: `{"tool": "img-optim", "version": "...", "date": "...", "archive": "...", "files": [...]}`,
+/// `files` holding the --report records of the archive.
+fn write_embedded_report(path: &Path, archive: &str, stats: &ArchiveStats) -> Result<()> {
+    let report = serde_json::json!({
+        "tool": "img-optim",
+        "version": env!("CARGO_PKG_VERSION"),
+        "date": humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        "archive": archive,
+        "files": stats.reports,
+    });
+    std::fs::write(path, serde_json::to_string_pretty(&report)?)?;
+    Ok(())
+}
+
+fn sha256_file(path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn create_parent(file_path: &Path) -> Result<()> {
     if let Some(parent) = file_path.parent() {
         if !parent.exists() {
             debug!("create directory {:?}", parent);
@@ -326,3 +4528,355 @@ fn escape_glob(input: &str) -> String {
         .replace("{", r#"\{"#)
         .replace("}", r#"\}"#)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Options as parsed from the command line, source and target included.
+    fn opts(args: &[&str]) -> Opts {
+        Opts::parse_from(["img-optim", "in.zip", "out.zip"].iter().chain(args))
+    }
+
+    /// A zip at `path` holding `entries`, each one stored as given, without compression.
+    fn write_zip(path: &Path, entries: &[(&str, &[u8])]) {
+        use zip::write::SimpleFileOptions;
+        let mut writer = zip::ZipWriter::new(std::fs::File::create(path).unwrap());
+        let options = SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        for (name, content) in entries {
+            writer.start_file(*name, options).unwrap();
+            writer.write_all(content).unwrap();
+        }
+        writer.finish().unwrap();
+    }
+
+    /// A 1x1 PNG.
+    const PNG: &[u8] = &[
+        0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x48, 0x44, 0x52,
+        0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x08, 0x06, 0x00, 0x00, 0x00, 0x1f, 0x15, 0xc4,
+        0x89, 0x00, 0x00, 0x00, 0x0a, 0x49, 0x44, 0x41, 0x54, 0x78, 0x9c, 0x63, 0x00, 0x01, 0x00, 0x00,
+        0x05, 0x00, 0x01, 0x0d, 0x0a, 0x2d, 0xb4, 0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4e, 0x44, 0xae,
+        0x42, 0x60, 0x82,
+    ];
+
+    /// A directory holding `script` as `gm`, and the --gm-env setting putting it first on the
+    /// PATH the gm commands are looked up in, leaving the test process's PATH alone.
+    #[cfg(unix)]
+    fn stub_gm(script: &str) -> (tempfile::TempDir, String) {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let gm = dir.path().join("gm");
+        std::fs::write(&gm, format!("#!/bin/sh\n{}\n", script)).unwrap();
+        std::fs::set_permissions(&gm, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let path = format!("PATH={}:{}", dir.path().display(), std::env::var("PATH").unwrap_or_default());
+        (dir, path)
+    }
+
+    /// A plain conversion of `input` to `result`, at the native size.
+    fn conversion(input: &Path, result: &Path) -> Conversion {
+        Conversion {
+            input: input.to_string_lossy().to_string(),
+            result: result.to_path_buf(),
+            geometry: None,
+            quality: "85".to_string(),
+            rotate: None,
+            crop: None,
+            to_rgb: false,
+            fit_crop: None,
+            auto_orient: false,
+            debug_dir: None,
+        }
+    }
+
+    #[test]
+    fn enclosed_path_refuses_escaping_entries() {
+        let root = Path::new("/tmp/img-optim-unpack");
+        for name in ["../x", "/etc/x", "a/../../x"] {
+            assert!(enclosed_path(root, name, Path::new(name)).is_err(), "{} was accepted", name);
+        }
+        assert_eq!(enclosed_path(root, "a/b/p1.jpg", Path::new("a/b/p1.jpg")).unwrap(), root.join("a/b/p1.jpg"));
+    }
+
+    #[test]
+    fn unpack_checks_crc_unless_disabled() {
+        let dir = tempfile::tempdir().unwrap();
+        let zip_path = dir.path().join("corrupted.zip");
+        write_zip(&zip_path, &[("p1.jpg", b"stored page data")]);
+        let mut bytes = std::fs::read(&zip_path).unwrap();
+        let data = bytes.windows(16).position(|window| window == b"stored page data").unwrap();
+        bytes[data] ^= 0xff;
+        std::fs::write(&zip_path, &bytes).unwrap();
+
+        let strict = tempfile::tempdir().unwrap();
+        let error = unpack_archive(&zip_path, strict.path(), &opts(&[])).err().expect("the corrupted entry was accepted");
+        assert!(error.to_string().contains("corrupted"), "unexpected error: {}", error);
+
+        let lenient = tempfile::tempdir().unwrap();
+        assert!(unpack_archive(&zip_path, lenient.path(), &opts(&["--no-verify-crc"])).is_ok());
+        assert_eq!(std::fs::read(lenient.path().join("p1.jpg")).unwrap(), &bytes[data..data + 16]);
+    }
+
+    #[test]
+    fn run_zip_replaces_the_previous_target() {
+        let out = tempfile::tempdir().unwrap();
+        let target = out.path().join("out.zip");
+        let first = tempfile::tempdir().unwrap();
+        std::fs::write(first.path().join("old.jpg"), b"old").unwrap();
+        std::fs::write(first.path().join("both.jpg"), b"old").unwrap();
+        run_zip(&first, &target).unwrap();
+        let second = tempfile::tempdir().unwrap();
+        std::fs::write(second.path().join("both.jpg"), b"new").unwrap();
+        std::fs::write(second.path().join("new.jpg"), b"new").unwrap();
+        run_zip(&second, &target).unwrap();
+
+        assert_eq!(count_zip_files(&target).unwrap(), 2);
+        let archive = zip::ZipArchive::new(std::fs::File::open(&target).unwrap()).unwrap();
+        let mut names: Vec<&str> = archive.file_names().collect();
+        names.sort();
+        assert_eq!(names, ["both.jpg", "new.jpg"]);
+    }
+
+    #[test]
+    fn unpack_refuses_zip_slip() {
+        let dir = tempfile::tempdir().unwrap();
+        let zip_path = dir.path().join("slip.zip");
+        write_zip(&zip_path, &[("p1.jpg", b"page"), ("../evil.txt", b"outside")]);
+        let unpack_dir = dir.path().join("unpack");
+        std::fs::create_dir(&unpack_dir).unwrap();
+        let error = unpack_archive(&zip_path, &unpack_dir, &opts(&[])).err().expect("the zip was unpacked");
+        assert!(error.to_string().contains("refusing"), "unexpected error: {}", error);
+        assert!(!dir.path().join("evil.txt").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn convert_image_fails_when_gm_writes_nothing() {
+        let (_stub, path) = stub_gm("exit 0");
+        let dir = tempfile::tempdir().unwrap();
+        let item = dir.path().join("p1.png");
+        std::fs::write(&item, b"page").unwrap();
+        let result = dir.path().join("p1.jpg");
+        let error = convert_image(&item, &conversion(&item, &result), &opts(&["--gm-env", &path]))
+            .expect_err("the missing output was accepted");
+        assert!(error.to_string().contains("didn't create"), "unexpected error: {}", error);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn failed_conversion_leaves_no_page_behind() {
+        // writes a partial output, then fails on the pages named `fail*`
+        let (_stub, path) = stub_gm(r#"for last; do :; done
+echo partial > "$last"
+case "$2" in *fail*) exit 1;; esac
+cp "$2" "$last""#);
+        let source = tempfile::tempdir().unwrap();
+        std::fs::write(source.path().join("p1.png"), PNG).unwrap();
+        std::fs::write(source.path().join("p2-fail.png"), PNG).unwrap();
+        let target = tempfile::tempdir().unwrap();
+        let stats = process_files(&source.path(), target.path(), &opts(&["--gm-env", &path]), None, &HashMap::new()).unwrap();
+
+        assert_eq!(stats.failures.len(), 1);
+        assert!(target.path().join("p1.jpg").is_file());
+        assert!(!target.path().join("p2-fail.jpg").exists());
+        let written = list_entries(target.path()).unwrap().iter()
+            .filter(|sub_path| target.path().join(sub_path).is_file())
+            .count();
+        check_page_count(&stats, written, "processed directory").unwrap();
+    }
+
+    #[test]
+    fn sniff_recognizes_misnamed_images() {
+        let dir = tempfile::tempdir().unwrap();
+        let page = dir.path().join("page.dat");
+        std::fs::write(&page, PNG).unwrap();
+        assert_eq!(sniffed_extension(&page).unwrap(), Some("png"));
+        std::fs::write(dir.path().join("notes.txt"), b"not an image").unwrap();
+        assert_eq!(sniffed_extension(&dir.path().join("notes.txt")).unwrap(), None);
+
+        let copied = tempfile::tempdir().unwrap();
+        let stats = process_files(&dir.path(), copied.path(), &opts(&["--sniff", "--quality", "copy"]), None, &HashMap::new()).unwrap();
+        assert_eq!(stats.copied, 2);
+        assert_eq!(std::fs::read(copied.path().join("page.png")).unwrap(), PNG);
+        assert!(!copied.path().join("page.dat").exists());
+        assert!(copied.path().join("notes.txt").is_file());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn sniff_converts_misnamed_images() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("page.dat"), PNG).unwrap();
+        let (_stub, path) = stub_gm(r#"for last; do :; done
+cp "$2" "$last""#);
+        let converted = tempfile::tempdir().unwrap();
+        process_files(&dir.path(), converted.path(), &opts(&["--sniff", "--gm-env", &path]), None, &HashMap::new()).unwrap();
+        assert!(converted.path().join("page.jpg").is_file());
+        assert!(!converted.path().join("page.dat").exists());
+    }
+
+    #[test]
+    fn image_options_hash_covers_image_options_and_pages() {
+        let no_pages = HashMap::new();
+        let hash = |args: &[&str], pages: &HashMap<String, PageOptions>| image_options_hash(&opts(args), pages).unwrap();
+        let base = hash(&[], &no_pages);
+        assert_eq!(hash(&["--jobs", "4", "--marker"], &no_pages), base);
+        for args in [&["--sniff"][..], &["--max-aspect-ratio", "3"], &["--gm-env", "OMP_NUM_THREADS=1"], &["--empty-policy", "skip"],
+                     &["--page-range", "10:20"]] {
+            assert_ne!(hash(args, &no_pages), base, "{:?} isn't hashed", args);
+        }
+        let pages = HashMap::from([("p1.jpg".to_string(), PageOptions { skip: true, ..PageOptions::default() })]);
+        assert_ne!(hash(&[], &pages), base);
+    }
+
+    #[test]
+    fn gm_convert_args_order() {
+        let opts = opts(&["--trim", "--normalize", "--contrast-stretch", "2x1", "--max-width", "800"]);
+        let conversion = Conversion {
+            crop: Some("50%x100%+0+0".to_string()),
+            geometry: Some("800x>".to_string()),
+            ..conversion(Path::new("p1.png"), Path::new("p1.jpg"))
+        };
+        assert!(!gm_convert_args(&conversion, &opts).contains(&"-auto-orient".to_string()));
+        let args = gm_convert_args(&Conversion { auto_orient: true, ..conversion }, &opts);
+        let position = |arg: &str| args.iter().position(|candidate| candidate == arg).unwrap_or_else(|| panic!("no {} in {:?}", arg, args));
+        let order = ["-auto-orient", "-crop", "-trim", "-normalize", "-level", "-resize", "-quality"];
+        for pair in order.windows(2) {
+            assert!(position(pair[0]) < position(pair[1]), "{} isn't before {} in {:?}", pair[0], pair[1], args);
+        }
+        assert_eq!(args.first().map(String::as_str), Some("convert"));
+        assert_eq!(args.last().map(String::as_str), Some("p1.jpg"));
+    }
+
+    #[test]
+    fn process_glob_applies_the_file_options() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("scans")).unwrap();
+        std::fs::write(dir.path().join("scans/tiny.png"), PNG).unwrap();
+        std::fs::write(dir.path().join("scans/notes.txt"), b"not an image").unwrap();
+        let out = dir.path().join("out");
+        let pattern = format!("{}/scans/*", dir.path().display());
+        process_glob(&pattern, &out, &opts(&["--min-dimension", "10", "--jobs", "2"])).unwrap();
+
+        assert_eq!(std::fs::read(out.join("tiny.png")).unwrap(), PNG);
+        assert!(!out.join("tiny.jpg").exists());
+        assert!(!out.join("notes.txt").exists());
+    }
+
+    #[test]
+    fn encoder_options_disable_the_jpegtran_path() {
+        let item = Path::new("p1.jpg");
+        assert!(is_orientation_only(item, "jpg", "jpg", &opts(&["--rotate-exif-only"])).unwrap());
+        for args in [&["--format-quality", "jpg=60"][..], &["--subsampling", "444"], &["--depth", "8"]] {
+            let opts = opts(&[&["--rotate-exif-only"][..], args].concat());
+            assert!(!is_orientation_only(item, "jpg", "jpg", &opts).unwrap(), "{:?} kept the jpegtran path", args);
+        }
+    }
+
+    #[test]
+    fn parse_gm_formats_reads_the_gm_listing() {
+        // no gm here: laid out after GraphicsMagick's ListMagickInfo, `"%10s%c %c  %c%c%c  %.70s"`
+        // (name, `*` for blob support, coder class P/S/U, read/write/multi-frame mode, description)
+        let listing = "   Format L  Mode  Description
+--------------------------------------------------------------------------------
+      AVIF  S  r--  AV1 Image File Format
+       GIF* S  rw+  CompuServe graphics interchange format
+      JPEG* S  rw-  Joint Photographic Experts Group JFIF format (IJG JPEG v6b)
+       PNG* S  rw-  Portable Network Graphics (libpng 1.6.43)
+      WEBP  S  rw-  Google WebP Image Format (libwebp 1.4.0)
+     8BIM* P  rw-  Photoshop resource format
+";
+        let formats = parse_gm_formats(listing);
+        let names: Vec<&str> = formats.iter().map(|format| format.name.as_str()).collect();
+        assert_eq!(names, ["AVIF", "GIF", "JPEG", "PNG", "WEBP", "8BIM"]);
+        let avif = &formats[0];
+        assert!(avif.readable && !avif.writable);
+        assert!(formats[1..].iter().all(|format| format.readable && format.writable));
+        assert!(parse_gm_formats("gm: unrecognized option `-list'\n").is_empty());
+    }
+
+    #[test]
+    fn update_reuses_only_the_outputs_of_the_options_in_effect() {
+        let dir = tempfile::tempdir().unwrap();
+        let previous = dir.path().join("previous.zip");
+        write_zip(&previous, &[("p1.jpg", b"p1"), ("p1-1.jpg", b"sibling"), ("p1@320.jpg", b"320"), ("p1@640.jpg", b"640"),
+                               ("anim.gif", b"gifsicle")]);
+        let source = dir.path().join("source");
+        std::fs::create_dir(&source).unwrap();
+        let item = source.join("p1.jpg");
+        std::fs::write(&item, b"source").unwrap();
+        std::fs::write(source.join("anim.gif"), b"source").unwrap();
+        let metadata = ArchiveMetadata {
+            modified: HashMap::from([(PathBuf::from("p1.jpg"), zip::DateTime::default()), (PathBuf::from("anim.gif"), zip::DateTime::default())]),
+            ..ArchiveMetadata::default()
+        };
+        let base = UpdateBase::open(&previous, &metadata, None).unwrap();
+        let reused_from = |item: &Path, args: &[&str]| {
+            let target = tempfile::tempdir().unwrap();
+            let mut names: Vec<String> = base.reuse(item, &source, target.path(), &opts(args)).unwrap().unwrap_or_default()
+                .iter()
+                .map(|path| entry_name(path.strip_prefix(target.path()).unwrap()))
+                .collect();
+            names.sort();
+            names
+        };
+        let reused = |args: &[&str]| reused_from(&item, args);
+        assert_eq!(reused(&[]), ["p1.jpg"]);
+        assert_eq!(reused(&["--split-spreads"]), ["p1-1.jpg", "p1.jpg"]);
+        assert_eq!(reused(&["--sizes", "320"]), ["p1@320.jpg"]);
+        assert_eq!(reused_from(&source.join("anim.gif"), &["--gifsicle"]), ["anim.gif"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn spreads_stored_sideways_are_split_as_displayed() {
+        // a JPEG stored 2x4 with EXIF orientation 6, displayed 4x2: a spread once turned upright
+        let jpeg: &[u8] = &[
+            0xff, 0xd8,
+            0xff, 0xe1, 0x00, 0x22, b'E', b'x', b'i', b'f', 0x00, 0x00,
+            b'M', b'M', 0x00, 0x2a, 0x00, 0x00, 0x00, 0x08,
+            0x00, 0x01, 0x01, 0x12, 0x00, 0x03, 0x00, 0x00, 0x00, 0x01, 0x00, 0x06, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+            0xff, 0xc0, 0x00, 0x11, 0x08, 0x00, 0x04, 0x00, 0x02, 0x03,
+            0x01, 0x22, 0x00, 0x02, 0x11, 0x01, 0x03, 0x11, 0x01,
+            0xff, 0xd9,
+        ];
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        std::fs::create_dir(&source).unwrap();
+        let item = source.join("p1.jpg");
+        std::fs::write(&item, jpeg).unwrap();
+        assert_eq!(exif_orientation(&item).unwrap(), Some(6));
+        let log = dir.path().join("gm.log");
+        let (_stub, path) = stub_gm(&format!(r#"echo "$@" >> {}
+for last; do :; done
+cp "$2" "$last""#, log.display()));
+        let target = dir.path().join("target");
+        let mut stats = ArchiveStats::default();
+        let produced = process_one_image(&item, &source, &target, &opts(&["--split-spreads", "--gm-env", &path]), None, &mut stats).unwrap();
+
+        assert_eq!(produced, [target.join("p1-1.jpg"), target.join("p1-2.jpg")]);
+        let log = std::fs::read_to_string(&log).unwrap();
+        for (line, crop) in log.lines().zip(["-crop 2x2+0+0", "-crop 2x2+2+0"]) {
+            assert!(line.contains(&format!("-auto-orient {}", crop)), "unexpected gm arguments: {}", line);
+        }
+        assert_eq!(log.lines().count(), 2);
+    }
+
+    #[test]
+    fn sanitized_names_reach_the_reports() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("p 1.jpg"), b"page").unwrap();
+        std::fs::write(dir.path().join("p2.jpg"), b"page").unwrap();
+        let mut stats = ArchiveStats {
+            reports: vec![FileReport { file: PathBuf::from("p 1.png"), outputs: vec![PathBuf::from("p 1.jpg")], duration_ms: 1, backend: "gm" }],
+            conversions: vec![(PathBuf::from("p 1.png"), vec![dir.path().join("p 1.jpg"), dir.path().join("p2.jpg")])],
+            ..ArchiveStats::default()
+        };
+        let renames = sanitize_names(dir.path(), "A-Za-z0-9._-").unwrap();
+        stats.rename_outputs(dir.path(), &renames);
+
+        assert_eq!(stats.reports[0].outputs, [PathBuf::from("p_1.jpg")]);
+        assert_eq!(stats.conversions[0].1, [dir.path().join("p_1.jpg"), dir.path().join("p2.jpg")]);
+        assert!(stats.conversions[0].1.iter().all(|output| output.is_file()));
+    }
+}